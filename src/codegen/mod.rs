@@ -1,9 +1,9 @@
-use crate::semantic_tree::{Node, Type, FunctionDefinition};
-use crate::wasm::{LocalId, TypeId, FuncId, module::Module, instruction::{Instruction, Expr, MemArg}, sections::*, core::ValueType};
+use crate::semantic_tree::{Node, NodeKind, Type, FunctionDefinition, BinaryOperator, UnaryOperator, MemoryWidth, LoadKind, typecheck::{TypeEnvironment, TypeCheckError, describe_type}};
+use crate::wasm::instruction::BlockType;
+use crate::diagnostic::{Span, SpannedError, Stage};
+use crate::wasm::{LocalId, TypeId, FuncId, GlobalId, module::Module, instruction::{Instruction, Expr, MemArg}, sections::*, core::ValueType};
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::fmt::{Display, Formatter};
-use std::error::Error;
+use std::sync::{Arc, Mutex};
 use bimap::BiHashMap;
 use code_section::Local;
 
@@ -14,13 +14,125 @@ pub struct CodeGenGlobalContext {
 
     // A mapping of function IDs to their function type and local types.
     pub function_table: HashMap<FuncId, (Type, Vec<Type>)>,
+
+    // A mapping of function IDs to their names and types, including imports.
+    pub functions: HashMap<FuncId, (String, Type)>,
+
+    // A mapping of global IDs to their types.
+    pub globals: HashMap<GlobalId, Type>,
+
+    // The string constants used by the program, which are filled in while
+    // generating instructions.
+    pub strings: Mutex<StringTable>,
+}
+
+// The address in memory where string constants start. Everything before this
+// is left free for programs to use as scratch space.
+pub const STRING_TABLE_BASE: u32 = 1024;
+
+// The size of a page of wasm memory, in bytes.
+const WASM_PAGE_SIZE: u32 = 65536;
+
+// Lays out string constants in memory, storing each distinct string once.
+pub struct StringTable {
+    pub data: Vec<u8>,
+    pub addresses: HashMap<Vec<u8>, u32>,
+}
+
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable { data: vec![], addresses: HashMap::new() }
+    }
+
+    // Gets the address of a string, adding it to the table if it's not there.
+    pub fn address_of(&mut self, bytes: &[u8]) -> u32 {
+        if let Some(address) = self.addresses.get(bytes) {
+            return *address;
+        }
+
+        let address = STRING_TABLE_BASE + self.data.len() as u32;
+        self.data.extend_from_slice(bytes);
+        self.addresses.insert(bytes.to_vec(), address);
+        address
+    }
+
+    // The first address after the end of the table.
+    pub fn end(&self) -> u32 {
+        STRING_TABLE_BASE + self.data.len() as u32
+    }
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // A per-function context for code generation.
 pub struct CodeGenContext {
     pub global: Arc<CodeGenGlobalContext>,
     pub parent: Option<Arc<CodeGenContext>>,
+
+    // The types of the function's locals, starting with its parameters.
     pub locals: Vec<Type>,
+
+    // The label introduced by the structured instruction this context was
+    // created for, if any.
+    pub label: Option<Label>,
+}
+
+impl CodeGenContext {
+    // Creates a context for code nested inside a structured instruction.
+    pub fn child(self: &Arc<Self>, label: Label) -> Arc<CodeGenContext> {
+        Arc::new(CodeGenContext {
+            global: self.global.clone(),
+            parent: Some(self.clone()),
+            locals: self.locals.clone(),
+            label: Some(label),
+        })
+    }
+
+    // Finds the relative depth of the innermost label of the given kind, for
+    // use as a branch target.
+    pub fn label_depth(&self, label: Label) -> Option<u32> {
+        let mut depth = 0;
+        let mut ctx = self;
+        loop {
+            match ctx.label {
+                Some(l) if l == label => return Some(depth),
+                Some(_) => depth += 1,
+                None => (),
+            }
+            ctx = ctx.parent.as_ref()?;
+        }
+    }
+}
+
+impl TypeEnvironment for CodeGenContext {
+    fn function(&self, id: FuncId) -> Option<(&str, &Type)> {
+        self.global.functions.get(&id).map(|(name, t)| (name.as_str(), t))
+    }
+
+    fn global(&self, id: GlobalId) -> Option<&Type> {
+        self.global.globals.get(&id)
+    }
+
+    fn local(&self, id: LocalId) -> Option<&Type> {
+        self.locals.get(id.0 as usize)
+    }
+}
+
+// The purpose of a wasm label, used to find the target of a branch.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Label {
+    // A label which tarn code can't branch to, such as an if's.
+    Anonymous,
+
+    // The label which exits a loop.
+    Break,
+
+    // The label which starts the next iteration of a loop.
+    Continue,
 }
 
 #[derive(Clone)]
@@ -29,47 +141,349 @@ pub enum SymbolLoc {
     Func(u32),
 }
 
-#[derive(Debug, Clone)]
-pub struct CodeGenError {
-    reason: String,
+pub enum CodeGenStage {}
+
+impl Stage for CodeGenStage {
+    const NAME: &'static str = "code gen";
 }
 
-impl CodeGenError {
-    fn new(reason: String) -> CodeGenError {
-        CodeGenError { reason }
+pub type CodeGenError = SpannedError<CodeGenStage>;
+
+// Code generation relies on the tree having been type checked already, so this
+// should only happen if the type checker was skipped
+impl From<TypeCheckError> for CodeGenError {
+    fn from(e: TypeCheckError) -> CodeGenError {
+        let error = CodeGenError::new(e.reason());
+        match e.span() {
+            Some(span) => error.or_at(span),
+            None => error,
+        }
     }
 }
 
-impl Display for CodeGenError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "code gen error: {}", self.reason)
+// Compresses a list of local types into wasm local declarations, each of which
+// declares a run of locals with the same type.
+fn group_locals(types: Vec<ValueType>) -> Vec<Local> {
+    let mut result: Vec<Local> = vec![];
+    for value_type in types {
+        match result.last_mut() {
+            Some(last) if last.value_type == value_type => last.n += 1,
+            _ => result.push(Local { n: 1, value_type }),
+        }
     }
+    result
 }
 
-impl Error for CodeGenError {}
+// Creates the memory argument for an access of the given width, aligned to
+// the width of the access.
+fn mem_arg(width: MemoryWidth) -> MemArg {
+    let align = match width {
+        MemoryWidth::Bits8 => 0,
+        MemoryWidth::Bits16 => 1,
+        MemoryWidth::Bits32 => 2,
+        MemoryWidth::Bits64 => 3,
+    };
+    MemArg { align, offset: 0 }
+}
 
 pub trait CodeGen {
-    fn generate_module(&self) -> Result<Module, CodeGenError>;
+    // Generating a module reports an error from every function which has one,
+    // but generating instructions stops at the first.
+    fn generate_module(&self) -> Result<Module, Vec<CodeGenError>>;
     fn generate_instructions(&self, ctx: Arc<CodeGenContext>) -> Result<Vec<Instruction>, CodeGenError>;
 }
 
+impl Node {
+    // Generates instructions for this node when its value isn't used, dropping
+    // the value if there is one.
+    fn generate_discarded(&self, ctx: Arc<CodeGenContext>) -> Result<Vec<Instruction>, CodeGenError> {
+        match &self.kind {
+            // An assignment whose value isn't used doesn't need to keep it
+            NodeKind::LocalTee(LocalId(id), value) => Ok([
+                value.generate_instructions(ctx.clone())?,
+                vec![Instruction::LocalSet(*id)],
+            ].concat()),
+            NodeKind::GlobalTee(GlobalId(id), value) => Ok([
+                value.generate_instructions(ctx.clone())?,
+                vec![Instruction::GlobalSet(*id)],
+            ].concat()),
+
+            _ => {
+                let mut result = self.generate_instructions(ctx.clone())?;
+                if self.result_type(&*ctx)?.is_some() {
+                    result.push(Instruction::Drop);
+                }
+                Ok(result)
+            }
+        }
+    }
+}
+
+// Picks the instruction for a binary operator whose operands have the given
+// type. Integer division, remainders, shifts and comparisons are all signed.
+// And and Or short-circuit, so they aren't a single instruction.
+fn binary_instruction(op: BinaryOperator, operand_type: Option<&Type>) -> Option<Instruction> {
+    use Instruction::*;
+    use BinaryOperator as Op;
+
+    Some(match (operand_type?, op) {
+        (Type::Int | Type::Bool, Op::Equal) => I32Eq,
+        (Type::Int | Type::Bool, Op::NotEqual) => I32Ne,
+        (Type::Int, Op::Add) => I32Add,
+        (Type::Int, Op::Subtract) => I32Sub,
+        (Type::Int, Op::Multiply) => I32Mul,
+        (Type::Int, Op::Divide) => I32DivS,
+        (Type::Int, Op::Modulo) => I32RemS,
+        (Type::Int, Op::BitwiseAnd) => I32And,
+        (Type::Int, Op::BitwiseOr) => I32Or,
+        (Type::Int, Op::BitwiseXor) => I32Xor,
+        (Type::Int, Op::ShiftLeft) => I32Shl,
+        (Type::Int, Op::ShiftRight) => I32ShrS,
+        (Type::Int, Op::LessThan) => I32LtS,
+        (Type::Int, Op::LessThanOrEqual) => I32LeS,
+        (Type::Int, Op::GreaterThan) => I32GtS,
+        (Type::Int, Op::GreaterThanOrEqual) => I32GeS,
+
+        (Type::Int64, Op::Equal) => I64Eq,
+        (Type::Int64, Op::NotEqual) => I64Ne,
+        (Type::Int64, Op::Add) => I64Add,
+        (Type::Int64, Op::Subtract) => I64Sub,
+        (Type::Int64, Op::Multiply) => I64Mul,
+        (Type::Int64, Op::Divide) => I64DivS,
+        (Type::Int64, Op::Modulo) => I64RemS,
+        (Type::Int64, Op::BitwiseAnd) => I64And,
+        (Type::Int64, Op::BitwiseOr) => I64Or,
+        (Type::Int64, Op::BitwiseXor) => I64Xor,
+        (Type::Int64, Op::ShiftLeft) => I64Shl,
+        (Type::Int64, Op::ShiftRight) => I64ShrS,
+        (Type::Int64, Op::LessThan) => I64LtS,
+        (Type::Int64, Op::LessThanOrEqual) => I64LeS,
+        (Type::Int64, Op::GreaterThan) => I64GtS,
+        (Type::Int64, Op::GreaterThanOrEqual) => I64GeS,
+
+        (Type::Float32, Op::Equal) => F32Eq,
+        (Type::Float32, Op::NotEqual) => F32Ne,
+        (Type::Float32, Op::Add) => F32Add,
+        (Type::Float32, Op::Subtract) => F32Sub,
+        (Type::Float32, Op::Multiply) => F32Mul,
+        (Type::Float32, Op::Divide) => F32Div,
+        (Type::Float32, Op::LessThan) => F32Lt,
+        (Type::Float32, Op::LessThanOrEqual) => F32Le,
+        (Type::Float32, Op::GreaterThan) => F32Gt,
+        (Type::Float32, Op::GreaterThanOrEqual) => F32Ge,
+
+        (Type::Float64, Op::Equal) => F64Eq,
+        (Type::Float64, Op::NotEqual) => F64Ne,
+        (Type::Float64, Op::Add) => F64Add,
+        (Type::Float64, Op::Subtract) => F64Sub,
+        (Type::Float64, Op::Multiply) => F64Mul,
+        (Type::Float64, Op::Divide) => F64Div,
+        (Type::Float64, Op::LessThan) => F64Lt,
+        (Type::Float64, Op::LessThanOrEqual) => F64Le,
+        (Type::Float64, Op::GreaterThan) => F64Gt,
+        (Type::Float64, Op::GreaterThanOrEqual) => F64Ge,
+
+        _ => return None,
+    })
+}
+
+// Picks the instructions which convert a value between two types, or None if
+// there's no such conversion. Integers are treated as signed, and converting a
+// float which doesn't fit in an integer traps. Bools are already 0 or 1, but
+// any Int other than 0 is true.
+fn conversion_instructions(from: &Type, to: &Type) -> Option<Vec<Instruction>> {
+    use Instruction::*;
+
+    Some(vec![match (from, to) {
+        (from, to) if from == to && from.can_cast_to(to) => return Some(vec![]),
+
+        (Type::Bool, Type::Int) => return Some(vec![]),
+        (Type::Int, Type::Bool) => return Some(vec![I32Const(0), I32Ne]),
+
+        (Type::Int, Type::Int64) => I64ExtendI32S,
+        (Type::Int, Type::Float32) => F32ConvertI32S,
+        (Type::Int, Type::Float64) => F64ConvertI32S,
+
+        (Type::Int64, Type::Int) => I32WrapI64,
+        (Type::Int64, Type::Float32) => F32ConvertI64S,
+        (Type::Int64, Type::Float64) => F64ConvertI64S,
+
+        (Type::Float32, Type::Int) => I32TruncF32S,
+        (Type::Float32, Type::Int64) => I64TruncF32S,
+        (Type::Float32, Type::Float64) => F64PromoteF32,
+
+        (Type::Float64, Type::Int) => I32TruncF64S,
+        (Type::Float64, Type::Int64) => I64TruncF64S,
+        (Type::Float64, Type::Float32) => F32DemoteF64,
+
+        _ => return None,
+    }])
+}
+
+// Whether a match's cases are close enough together that a branch table
+// indexed by the value is worth its size.
+fn is_dense(cases: &[(i64, Node)]) -> bool {
+    let min = cases.iter().map(|(n, _)| *n).min();
+    let max = cases.iter().map(|(n, _)| *n).max();
+    match (min, max) {
+        (Some(min), Some(max)) => cases.len() >= MIN_TABLE_CASES && max.saturating_sub(min) < 2 * cases.len() as i64,
+        _ => false,
+    }
+}
+
+// The fewest cases for which a match uses a branch table rather than a chain of ifs.
+const MIN_TABLE_CASES: usize = 3;
+
+impl Node {
+    // The block type of a structured instruction which produces this node's value.
+    fn block_type(&self, ctx: &Arc<CodeGenContext>) -> Result<BlockType, CodeGenError> {
+        match self.result_type(&**ctx)? {
+            Some(t) => Ok(BlockType::ValueType(t
+                .to_wasm_value_type()
+                .ok_or_else(|| CodeGenError::new(format!("a block can't produce a value of type {}", t)))?)),
+            None => Ok(BlockType::Empty),
+        }
+    }
+
+    // Generates an arm of a match, keeping its value only if the match has one.
+    fn generate_arm(&self, keep_value: bool, ctx: Arc<CodeGenContext>) -> Result<Vec<Instruction>, CodeGenError> {
+        if keep_value {
+            self.generate_instructions(ctx)
+        } else {
+            self.generate_discarded(ctx)
+        }
+    }
+
+    // Lowers a match to a branch table, jumping into a stack of blocks with
+    // the body of each case following the end of its block:
+    //
+    //   block $end
+    //     block $default
+    //       block $case1
+    //         block $case0
+    //           br_table $case0 $case1 ... $default
+    //         end
+    //         case 0; br $end
+    //       end
+    //       case 1; br $end
+    //     end
+    //     default
+    //   end
+    fn generate_branch_table(&self, ctx: Arc<CodeGenContext>) -> Result<Vec<Instruction>, CodeGenError> {
+        use Instruction::*;
+
+        let (scratch, cases, default) = match &self.kind {
+            NodeKind::Match(LocalId(scratch), _, cases, default) => (*scratch, cases, default),
+            _ => return Err(CodeGenError::new("only a match can use a branch table")),
+        };
+        let block_type = self.block_type(&ctx)?;
+        let keep_value = !matches!(block_type, BlockType::Empty);
+        let n = cases.len() as u32;
+
+        // The contexts of each block, from the outside in
+        let end_ctx = ctx.child(Label::Anonymous);
+        let default_ctx = end_ctx.child(Label::Anonymous);
+        let mut case_ctxs = vec![default_ctx.child(Label::Anonymous)];
+        for _ in 1..cases.len() {
+            let inner = case_ctxs.last().unwrap().child(Label::Anonymous);
+            case_ctxs.push(inner);
+        }
+        case_ctxs.reverse();
+
+        // Case i's block is at depth i from the table, and values without a case go to the default
+        let min = cases.iter().map(|(n, _)| *n).min().unwrap_or(0);
+        let max = cases.iter().map(|(n, _)| *n).max().unwrap_or(0);
+        let labels = (min..=max)
+            .map(|value| cases.iter().position(|(n, _)| *n == value).map_or(n, |i| i as u32))
+            .collect();
+        let mut result = vec![LocalGet(scratch), I32Const(min as i32), I32Sub, BranchTable(labels, n)];
+
+        for (i, (_, body)) in cases.iter().enumerate() {
+            // The body follows the end of its block, so it's inside the next one out
+            let body_ctx = case_ctxs.get(i + 1).unwrap_or(&default_ctx).clone();
+            result = [
+                vec![Instruction::Block(BlockType::Empty, result)],
+                body.generate_arm(keep_value, body_ctx)?,
+                vec![Branch(n - i as u32)],
+            ].concat();
+        }
+
+        result = vec![Instruction::Block(BlockType::Empty, result)];
+        if let Some(default) = default {
+            result.append(&mut default.generate_arm(keep_value, end_ctx)?);
+        }
+        Ok(vec![Instruction::Block(block_type, result)])
+    }
+
+    // Lowers a match to a chain of ifs, comparing the value with each case in turn.
+    fn generate_if_chain(&self, ctx: Arc<CodeGenContext>) -> Result<Vec<Instruction>, CodeGenError> {
+        use Instruction::*;
+
+        let (scratch, cases, default) = match &self.kind {
+            NodeKind::Match(LocalId(scratch), _, cases, default) => (*scratch, cases, default),
+            _ => return Err(CodeGenError::new("only a match can use an if chain")),
+        };
+        let block_type = self.block_type(&ctx)?;
+        let keep_value = !matches!(block_type, BlockType::Empty);
+
+        // Each case's if is nested in the else of the one before
+        let mut case_ctxs = vec![];
+        let mut outer = ctx;
+        for _ in cases {
+            outer = outer.child(Label::Anonymous);
+            case_ctxs.push(outer.clone());
+        }
+
+        let mut result = match default {
+            Some(default) => default.generate_arm(keep_value, outer)?,
+            None => vec![],
+        };
+        for ((value, body), case_ctx) in cases.iter().zip(case_ctxs).rev() {
+            result = vec![
+                LocalGet(scratch),
+                I32Const(*value as i32),
+                I32Eq,
+                IfElse(block_type.clone(), body.generate_arm(keep_value, case_ctx)?, result),
+            ];
+        }
+        Ok(result)
+    }
+}
+
 impl CodeGen for Node {
-    fn generate_module<'a>(&self) -> Result<Module, CodeGenError> {
-        if let Node::Root(children) = self {
+    fn generate_module<'a>(&self) -> Result<Module, Vec<CodeGenError>> {
+        if let NodeKind::Root(children) = &self.kind {
             // Create the function and type tables
             let mut type_table = BiHashMap::new();
             let mut function_vec = Box::new(vec![]);
             let mut import_vec = vec![];
+            let mut export_vec: Vec<(FuncId, String, Span)> = vec![];
+            let mut functions = HashMap::new();
+            let mut globals = HashMap::new();
+            let mut global_vec = vec![];
 
-            // Iterate over all functions at the root
+            // Iterate over all functions and globals at the root
             for child in children {
-                if let Node::FunctionDeclaration(id, func_type, def) = child {
+                if let NodeKind::GlobalDeclaration(id, _, global_type, value) = &child.kind {
+                    globals.insert(*id, global_type.clone());
+                    global_vec.push((*id, global_type.clone(), value));
+                } else if let NodeKind::ConstantDeclaration(..) = &child.kind {
+                    // Constants have already been inlined
+                } else if let NodeKind::FunctionDeclaration(id, name, func_type, def) = &child.kind {
+                    functions.insert(*id, (name.clone(), func_type.clone()));
+
                     // Create a function table entry
                     match def {
-                        FunctionDefinition::Implementation(locals, body) =>
-                            function_vec.push((func_type.clone(), locals.clone())),
+                        FunctionDefinition::Implementation(locals, _, export_names) => {
+                            function_vec.push((func_type.clone(), locals.clone()));
+                            for export_name in export_names {
+                                export_vec.push((*id, export_name.clone(), child.span));
+                            }
+                        },
                         FunctionDefinition::Import(module, name) =>
-                            import_vec.push((module.clone(), name.clone())),
+                            import_vec.push((module.clone(), name.clone(), func_type.clone())),
+                        FunctionDefinition::Invalid =>
+                            return Err(CodeGenError::at(child.span, format!("{} has errors, so it can't be compiled", name)).into()),
                     };
 
                     // Create a type table entry, if there isn't one
@@ -77,7 +491,7 @@ impl CodeGen for Node {
                         type_table.insert(TypeId(type_table.len() as u32), func_type.clone());
                     }
                 } else {
-                    return Err(CodeGenError::new("root must only contain valid function definitions".into()));
+                    return Err(CodeGenError::new("root must only contain function, global and constant declarations").into());
                 }
             }
 
@@ -93,33 +507,50 @@ impl CodeGen for Node {
             // Create the global context
             let global_context = Arc::new(CodeGenGlobalContext {
                 type_table: type_table.clone(),
-                function_table: function_table.clone()
+                function_table: function_table.clone(),
+                functions,
+                globals,
+                strings: Mutex::new(StringTable::new()),
             });
 
-            // Create a code table
+            // Create a code table, collecting errors from every function
             let mut code_table: HashMap<FuncId, Vec<Instruction>> = HashMap::new();
+            let mut errors = vec![];
 
             // Iterate over all functions at the root, again
             for child in children {
-                if let Node::FunctionDeclaration(id, _, def) = child {
+                if let NodeKind::FunctionDeclaration(id, _, func_type, def) = &child.kind {
                     // If this is a function implementation, create a function context and generate code
-                    if let FunctionDefinition::Implementation(locals, body) = def {
+                    if let FunctionDefinition::Implementation(locals, body, _) = def {
+                        let params = match func_type {
+                            Type::Function(params, _) => params.clone(),
+                            _ => return Err(CodeGenError::new("function declaration must have a function type").into()),
+                        };
                         let context = Arc::new(CodeGenContext {
-                            global: global_context.clone(), parent: None, locals: locals.clone(),
+                            global: global_context.clone(), parent: None, locals: [params, locals.clone()].concat(), label: None,
                         });
 
-                        code_table.insert(*id, body.generate_instructions(context)?);
+                        match body.generate_instructions(context) {
+                            Ok(code) => { code_table.insert(*id, code); },
+                            Err(e) => errors.push(e),
+                        }
                     }
+                } else if let NodeKind::GlobalDeclaration(..) | NodeKind::ConstantDeclaration(..) = &child.kind {
+                    // These don't have any code
                 } else {
-                    return Err(CodeGenError::new("root must only contain valid function definitions".into()));
+                    return Err(CodeGenError::new("root must only contain function, global and constant declarations").into());
                 }
             }
 
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+
             // TODO: validate keys are sequential
 
             // Ensure that the keys for both the function and code tables are the same
             if code_table.keys().len() != function_table.keys().len() || !code_table.keys().all(|x|  function_table.contains_key(x)) {
-                return Err(CodeGenError::new("code and function table key mismatch".into()));
+                return Err(CodeGenError::new("code and function table key mismatch").into());
             }
             let mut function_ids: Vec<FuncId> = code_table.keys().cloned().collect();
             function_ids.sort();
@@ -130,10 +561,10 @@ impl CodeGen for Node {
             let mut func_types: Vec<type_section::FuncType> = vec![];
             for id in type_ids {
                 let t = type_table.get_by_left(id)
-                    .ok_or(CodeGenError::new("missing type key".into()))?;
+                    .ok_or(CodeGenError::new("missing type key"))?;
 
                     func_types.push(t.to_wasm_func_type()
-                    .ok_or(CodeGenError::new("unable to convert function type".into()))?);
+                    .ok_or(CodeGenError::new("unable to convert function type"))?);
             }
             let type_section = TypeSection { func_types };
 
@@ -142,96 +573,171 @@ impl CodeGen for Node {
             let mut functions: Vec<u32> = vec![];
             for id in function_ids {
                 let (func_type, locals) = function_table.get(&id)
-                    .ok_or(CodeGenError::new("missing function key".into()))?;
+                    .ok_or(CodeGenError::new("missing function key"))?;
 
                 functions.push(type_table.get_by_right(func_type)
-                    .ok_or(CodeGenError::new("no function type".into()))?.0);
+                    .ok_or(CodeGenError::new("no function type"))?.0);
 
                 let code = code_table.get(&id)
-                    .ok_or(CodeGenError::new("missing code key".into()))?;
+                    .ok_or(CodeGenError::new("missing code key"))?;
 
                 codes.push(code_section::Code {
                     func: code_section::Func {
-                        locals: locals
+                        locals: group_locals(locals
                             .iter()
                             .map(|x| (*x).to_wasm_value_type())
                             .collect::<Option<Vec<ValueType>>>()
-                            .ok_or(CodeGenError::new("unable to convert local type".into()))?
-                            .iter()
-                            .enumerate()
-                            .map(|(n, t)| Local { n: n as u32, value_type: t.clone() } )
-                            .collect(),
+                            .ok_or(CodeGenError::new("unable to convert local type"))?),
                         expr: Expr { instructions: (*code).clone() }
                     }
                 })
             }
-            let imports = import_vec
-                .iter()
-                .enumerate()
-                .map(|(i, (module, name))| import_section::Import {
-                    desc: import_section::ImportDesc::Func(i as u32),
-                    module: module.clone(),
-                    name: name.clone(),
-                })
-                .collect();
+            let mut imports = vec![];
+            for (module, name, func_type) in import_vec {
+                imports.push(import_section::Import {
+                    desc: import_section::ImportDesc::Func(type_table.get_by_right(&func_type)
+                        .ok_or(CodeGenError::new("no function type"))?.0),
+                    module,
+                    name,
+                });
+            }
             let code_section = CodeSection { codes };
             let function_section = FunctionSection { types: functions };
             let import_section = ImportSection { imports };
 
+            // Create the global section. Globals can only be assigned by tarn code,
+            // so they're all mutable
+            global_vec.sort_by_key(|(id, _, _)| *id);
+            let global_init_context = Arc::new(CodeGenContext {
+                global: global_context.clone(), parent: None, locals: vec![], label: None,
+            });
+            let mut wasm_globals = vec![];
+            for (_, global_type, value) in global_vec {
+                wasm_globals.push(global_section::Global {
+                    global_type: global_section::GlobalType {
+                        value_type: global_type.to_wasm_value_type()
+                            .ok_or_else(|| CodeGenError::new(format!("a global can't have type {}", global_type)))?,
+                        mutable: true,
+                    },
+                    expr: Expr { instructions: value.generate_instructions(global_init_context.clone())? },
+                });
+            }
+            let global_sections = if wasm_globals.is_empty() {
+                vec![]
+            } else {
+                vec![GlobalSection { globals: wasm_globals }]
+            };
+
+            // Create the data section, which holds the string table
+            let strings = global_context.strings.lock().expect("string table lock poisoned");
+            let data_sections = if strings.data.is_empty() {
+                vec![]
+            } else {
+                vec![DataSection {
+                    data: vec![data_section::Data {
+                        memory: 0,
+                        expr: Expr { instructions: vec![Instruction::I32Const(STRING_TABLE_BASE as i32)] },
+                        init: strings.data.clone(),
+                    }]
+                }]
+            };
+
+            // Export the memory, and any functions which asked to be
+            let mut exports = vec![
+                export_section::Export {
+                    desc: export_section::ExportDesc::Mem(0),
+                    name: "memory".into(),
+                }
+            ];
+            for (FuncId(id), name, span) in export_vec {
+                if exports.iter().any(|export| export.name == name) {
+                    errors.push(CodeGenError::at(span, format!("more than one export is named {}", name)));
+                    continue;
+                }
+                exports.push(export_section::Export { desc: export_section::ExportDesc::Func(id), name });
+            }
+
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+
+            // Make sure there's enough memory for the string table
+            let memory_pages = strings.end().div_ceil(WASM_PAGE_SIZE);
+
             // Build a module
             Ok(Module {
                 type_sections: vec![type_section],
                 function_sections: vec![function_section],
                 code_sections: vec![code_section],
-                data_sections: vec![],
+                data_sections,
+                global_sections,
                 memory_sections: vec![MemorySection {
                     memories: vec![
                         memory_section::Memory {
                             memory_type: crate::wasm::core::Limits {
-                                min: 1,
+                                min: memory_pages,
                                 max: None,
                             }
                         }
                     ]
                 }],
-                export_sections: vec![ExportSection {
-                    exports: vec![
-                        export_section::Export {
-                            desc: export_section::ExportDesc::Mem(0),
-                            name: "memory".into(),
-                        }
-                    ]
-                }],
+                export_sections: vec![ExportSection { exports }],
                 import_sections: vec![import_section],
             })
         } else {
-            Err(CodeGenError::new("must generate module on a root node".into()))
+            Err(CodeGenError::new("must generate module on a root node").into())
         }
     }
 
     fn generate_instructions(&self, ctx: Arc<CodeGenContext>) -> Result<Vec<Instruction>, CodeGenError> {
-        use Node::*;
+        use NodeKind::*;
         use Instruction::*;
 
-        match self {
-            // TODO: all integer constants are i32 currently
+        match &self.kind {
+            // Semanticize has already checked that constants fit their type
             IntegerConstant(i) => Ok(vec![ I32Const(*i as i32) ]),
+            Int64Constant(i) => Ok(vec![ I64Const(*i) ]),
+            BoolConstant(b) => Ok(vec![ I32Const(*b as i32) ]),
+            Float32Constant(f) => Ok(vec![ F32Const(*f) ]),
+            Float64Constant(f) => Ok(vec![ F64Const(*f) ]),
+
+            StringConstant(bytes) => Ok(vec![
+                I32Const(ctx.global.strings.lock().expect("string table lock poisoned").address_of(bytes) as i32)
+            ]),
 
             Local(LocalId(id)) => Ok(vec![ LocalGet(*id) ]),
 
-            // TODO: make these more iterator
-            // TODO: bad implementation, doesn't create a context or honor termination
-            // TODO: drop unused values
-            Node::Block(ists, _) => {
-                let mut result: Vec<Instruction> = vec![];
-                for ist in ists {
-                    let mut this = ist.generate_instructions(ctx.clone())?;
-                    result.append(&mut this);
-                }
-                Ok(result)
-            }
+            NodeKind::GlobalGet(GlobalId(id)) => Ok(vec![ Instruction::GlobalGet(*id) ]),
+
+            // Wasm has no global.tee, so get the value back after setting it
+            NodeKind::GlobalTee(GlobalId(id), value) => Ok([
+                value.generate_instructions(ctx.clone())?,
+                vec![Instruction::GlobalSet(*id), Instruction::GlobalGet(*id)],
+            ].concat()),
+
+            NodeKind::LocalSet(LocalId(id), value) => Ok([
+                value.generate_instructions(ctx.clone())?,
+                vec![Instruction::LocalSet(*id)],
+            ].concat()),
+
+            NodeKind::LocalTee(LocalId(id), value) => Ok([
+                value.generate_instructions(ctx.clone())?,
+                vec![Instruction::LocalTee(*id)],
+            ].concat()),
+
+            // Every statement's value is dropped, except the last one in an unterminated block
+            NodeKind::Block(ists, term) => Ok(ists
+                .iter()
+                .enumerate()
+                .map(|(i, ist)| if *term || i + 1 < ists.len() {
+                    ist.generate_discarded(ctx.clone())
+                } else {
+                    ist.generate_instructions(ctx.clone())
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .concat()),
 
-            Node::Call(FuncId(id), args) => {
+            NodeKind::Call(FuncId(id), args) => {
                 let mut result: Vec<Instruction> = vec![];
                 for arg in args {
                     let mut this = arg.generate_instructions(ctx.clone())?;
@@ -241,19 +747,341 @@ impl CodeGen for Node {
                 Ok(result)
             },
 
-            Node::MemSet(addr, expr) => {
+            NodeKind::MemSet(width, addr, expr) => Ok([
+                addr.generate_instructions(ctx.clone())?,
+                expr.generate_instructions(ctx.clone())?,
+                vec![match (width, expr.result_type(&*ctx)?) {
+                    (MemoryWidth::Bits8, Some(Type::Int64)) => I64Store8(mem_arg(*width)),
+                    (MemoryWidth::Bits16, Some(Type::Int64)) => I64Store16(mem_arg(*width)),
+                    (MemoryWidth::Bits32, Some(Type::Int64)) => I64Store32(mem_arg(*width)),
+                    (MemoryWidth::Bits64, Some(Type::Int64)) => I64Store(mem_arg(*width)),
+                    (MemoryWidth::Bits32, Some(Type::Float32)) => F32Store(mem_arg(*width)),
+                    (MemoryWidth::Bits64, Some(Type::Float64)) => F64Store(mem_arg(*width)),
+                    (MemoryWidth::Bits8, _) => I32Store8(mem_arg(*width)),
+                    (MemoryWidth::Bits16, _) => I32Store16(mem_arg(*width)),
+                    (MemoryWidth::Bits32, _) => I32Store(mem_arg(*width)),
+                    (MemoryWidth::Bits64, t) =>
+                        return Err(CodeGenError::new(format!("can't store 64 bits of {}", describe_type(t.as_ref())))),
+                }],
+            ].concat()),
+
+            NodeKind::MemGet(width, kind, address) => Ok([
+                address.generate_instructions(ctx.clone())?,
+                vec![match (width, kind) {
+                    (MemoryWidth::Bits32, LoadKind::Float) => F32Load(mem_arg(*width)),
+                    (MemoryWidth::Bits64, LoadKind::Float) => F64Load(mem_arg(*width)),
+                    (MemoryWidth::Bits8, LoadKind::Signed) => I32Load8S(mem_arg(*width)),
+                    (MemoryWidth::Bits8, _) => I32Load8U(mem_arg(*width)),
+                    (MemoryWidth::Bits16, LoadKind::Signed) => I32Load16S(mem_arg(*width)),
+                    (MemoryWidth::Bits16, _) => I32Load16U(mem_arg(*width)),
+                    (MemoryWidth::Bits32, _) => I32Load(mem_arg(*width)),
+                    (MemoryWidth::Bits64, _) => I64Load(mem_arg(*width)),
+                }],
+            ].concat()),
+
+            // These short-circuit, so the right operand is only evaluated if needed
+            NodeKind::BinaryOperation(BinaryOperator::And, left, right) => Ok([
+                left.generate_instructions(ctx.clone())?,
+                vec![IfElse(
+                    BlockType::ValueType(ValueType::I32),
+                    right.generate_instructions(ctx.child(Label::Anonymous))?,
+                    vec![I32Const(0)],
+                )],
+            ].concat()),
+            NodeKind::BinaryOperation(BinaryOperator::Or, left, right) => Ok([
+                left.generate_instructions(ctx.clone())?,
+                vec![IfElse(
+                    BlockType::ValueType(ValueType::I32),
+                    vec![I32Const(1)],
+                    right.generate_instructions(ctx.child(Label::Anonymous))?,
+                )],
+            ].concat()),
+
+            NodeKind::BinaryOperation(op, left, right) => {
+                let operand_type = left.result_type(&*ctx)?;
+                let instruction = binary_instruction(*op, operand_type.as_ref())
+                    .ok_or_else(|| CodeGenError::new(format!("no instruction for {:?} on {}", op, describe_type(operand_type.as_ref()))))?;
+
                 Ok([
-                    addr.generate_instructions(ctx.clone())?,
-                    expr.generate_instructions(ctx.clone())?,
-                    vec![I32Store(MemArg { align: 2, offset: 0, })],
+                    left.generate_instructions(ctx.clone())?,
+                    right.generate_instructions(ctx.clone())?,
+                    vec![instruction],
                 ].concat())
-            }
+            },
+
+            NodeKind::UnaryOperation(UnaryOperator::Not, value) => Ok([
+                value.generate_instructions(ctx.clone())?,
+                vec![I32Eqz],
+            ].concat()),
 
-            Node::FunctionDeclaration(_, _, _) =>
-                Err(CodeGenError::new("can't generate instructions for a function definition".into())),
+            NodeKind::If(condition, then, otherwise) => {
+                let block_type = self.block_type(&ctx)?;
 
-            Node::Root(_) =>
-                Err(CodeGenError::new("can't generate instructions for a root".into())),
+                Ok([
+                    condition.generate_instructions(ctx.clone())?,
+                    vec![match otherwise {
+                        Some(otherwise) => Instruction::IfElse(
+                            block_type,
+                            then.generate_instructions(ctx.child(Label::Anonymous))?,
+                            otherwise.generate_instructions(ctx.child(Label::Anonymous))?,
+                        ),
+                        None => Instruction::If(block_type, then.generate_discarded(ctx.child(Label::Anonymous))?),
+                    }],
+                ].concat())
+            },
+
+            // Loops are a block to break out of, around a loop to continue from
+            NodeKind::While(condition, body) => {
+                let break_ctx = ctx.child(Label::Break);
+                let continue_ctx = break_ctx.child(Label::Continue);
+
+                Ok(vec![
+                    Instruction::Block(BlockType::Empty, vec![
+                        Instruction::Loop(BlockType::Empty, [
+                            condition.generate_instructions(continue_ctx.clone())?,
+                            vec![I32Eqz, BranchIf(1)],
+                            body.generate_discarded(continue_ctx.clone())?,
+                            vec![Branch(0)],
+                        ].concat()),
+                    ]),
+                ])
+            },
+
+            NodeKind::Cast(value, target) => {
+                let value_type = value.result_type(&*ctx)?;
+                let conversion = match value_type.as_ref() {
+                    Some(t) => conversion_instructions(t, target),
+                    None => None,
+                };
+
+                Ok([
+                    value.generate_instructions(ctx.clone())?,
+                    match conversion {
+                        Some(instructions) => instructions,
+                        None => return Err(CodeGenError::new(format!(
+                            "can't cast {} to {}", describe_type(value_type.as_ref()), target,
+                        ))),
+                    },
+                ].concat())
+            },
+
+            // The value is kept in a local, since it's needed by every comparison
+            NodeKind::Match(LocalId(scratch), value, cases, _) => Ok([
+                value.generate_instructions(ctx.clone())?,
+                vec![Instruction::LocalSet(*scratch)],
+                if is_dense(cases) {
+                    self.generate_branch_table(ctx)?
+                } else {
+                    self.generate_if_chain(ctx)?
+                },
+            ].concat()),
+
+            NodeKind::Break => Ok(vec![Branch(
+                ctx.label_depth(Label::Break)
+                    .ok_or_else(|| CodeGenError::at(self.span, "break must be inside a loop"))?
+            )]),
+
+            NodeKind::Continue => Ok(vec![Branch(
+                ctx.label_depth(Label::Continue)
+                    .ok_or_else(|| CodeGenError::at(self.span, "continue must be inside a loop"))?
+            )]),
+
+            NodeKind::FunctionDeclaration(..) =>
+                Err(CodeGenError::new("can't generate instructions for a function definition")),
+
+            NodeKind::GlobalDeclaration(..) | NodeKind::ConstantDeclaration(..) =>
+                Err(CodeGenError::new("can't generate instructions for a global or constant definition")),
+
+            NodeKind::Root(_) =>
+                Err(CodeGenError::new("can't generate instructions for a root")),
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use crate::semantic_tree::{semanticize::Semanticize, typecheck::TypeCheck};
+
+    // Compiles a program, returning the instructions of its last function.
+    fn compile_last_function(source: &str) -> Vec<Instruction> {
+        let semantic = parser::parse(source).expect("parse failed").to_semantic_tree().expect("semanticize failed");
+        semantic.type_check().expect("type check failed");
+        let module = semantic.generate_module().expect("code gen failed");
+        module.code_sections[0].codes.last().expect("no functions").func.expr.instructions.clone()
+    }
+
+    // Makes a context for the body of a function, with nothing around it.
+    fn function_context() -> Arc<CodeGenContext> {
+        Arc::new(CodeGenContext {
+            global: Arc::new(CodeGenGlobalContext {
+                type_table: BiHashMap::new(),
+                function_table: HashMap::new(),
+                functions: HashMap::new(),
+                globals: HashMap::new(),
+                strings: Mutex::new(StringTable::new()),
+            }),
+            parent: None,
+            locals: vec![],
+            label: None,
+        })
+    }
+
+    #[test]
+    fn label_depth() {
+        let function = function_context();
+        assert_eq!(function.label_depth(Label::Break), None);
+        assert_eq!(function.label_depth(Label::Continue), None);
+
+        // A loop is a block to break out of, with a loop to continue inside it
+        let outer_break = function.child(Label::Break);
+        let outer_continue = outer_break.child(Label::Continue);
+        assert_eq!(outer_continue.label_depth(Label::Continue), Some(0));
+        assert_eq!(outer_continue.label_depth(Label::Break), Some(1));
+
+        // Other blocks in between count towards the depth
+        let if_block = outer_continue.child(Label::Anonymous);
+        assert_eq!(if_block.label_depth(Label::Continue), Some(1));
+        assert_eq!(if_block.label_depth(Label::Break), Some(2));
+
+        // The innermost loop is the target
+        let inner_continue = if_block.child(Label::Break).child(Label::Continue);
+        assert_eq!(inner_continue.label_depth(Label::Continue), Some(0));
+        assert_eq!(inner_continue.label_depth(Label::Break), Some(1));
+    }
+
+    #[test]
+    fn dense_match_uses_branch_table() {
+        use Instruction::*;
+
+        // Local 0 is x, and local 1 holds the value being matched. There's no
+        // case for 3, so it goes to the default.
+        let code = compile_last_function("fn f(x: Int) -> Int match x { 1 => 10, 2 => 20, 4 => 40, _ => 0 }");
+        assert_eq!(code, vec![
+            LocalGet(0), LocalSet(1),
+            Block(BlockType::ValueType(ValueType::I32), vec![
+                Block(BlockType::Empty, vec![
+                    Block(BlockType::Empty, vec![
+                        Block(BlockType::Empty, vec![
+                            Block(BlockType::Empty, vec![
+                                LocalGet(1), I32Const(1), I32Sub, BranchTable(vec![0, 1, 3, 2], 3),
+                            ]),
+                            I32Const(10), Branch(3),
+                        ]),
+                        I32Const(20), Branch(2),
+                    ]),
+                    I32Const(40), Branch(1),
+                ]),
+                I32Const(0),
+            ]),
+        ]);
+    }
+
+    #[test]
+    fn sparse_match_uses_if_chain() {
+        use Instruction::*;
+
+        let i32_block = || BlockType::ValueType(ValueType::I32);
+        let code = compile_last_function("fn f(x: Int) -> Int match x { 1 => 10, 100 => 20, 1000 => 30, _ => 0 }");
+        assert_eq!(code, vec![
+            LocalGet(0), LocalSet(1),
+            LocalGet(1), I32Const(1), I32Eq,
+            IfElse(i32_block(), vec![I32Const(10)], vec![
+                LocalGet(1), I32Const(100), I32Eq,
+                IfElse(i32_block(), vec![I32Const(20)], vec![
+                    LocalGet(1), I32Const(1000), I32Eq,
+                    IfElse(i32_block(), vec![I32Const(30)], vec![I32Const(0)]),
+                ]),
+            ]),
+        ]);
+    }
+
+    #[test]
+    fn match_without_default_falls_through() {
+        use Instruction::*;
+
+        // Values without a case branch to the empty default block
+        let code = compile_last_function("
+            import fn env log(x: Int);
+            fn f(x: Int) match x { 1 => log(1), 2 => log(2), 3 => log(3) }
+        ");
+        assert_eq!(code, vec![
+            LocalGet(0), LocalSet(1),
+            Block(BlockType::Empty, vec![
+                Block(BlockType::Empty, vec![
+                    Block(BlockType::Empty, vec![
+                        Block(BlockType::Empty, vec![
+                            Block(BlockType::Empty, vec![
+                                LocalGet(1), I32Const(1), I32Sub, BranchTable(vec![0, 1, 2], 3),
+                            ]),
+                            I32Const(1), Call(0), Branch(3),
+                        ]),
+                        I32Const(2), Call(0), Branch(2),
+                    ]),
+                    I32Const(3), Call(0), Branch(1),
+                ]),
+            ]),
+        ]);
+    }
+
+    #[test]
+    fn branch_table_match_in_loop() {
+        use Instruction::*;
+
+        // From case 0, the blocks out are case 1, case 2, the default, the
+        // match, the loop and then the block which break exits. Case 1 is one
+        // block further out.
+        let code = compile_last_function("
+            import fn env log(x: Int);
+            fn f(x: Int) while true { match x { 1 => break, 2 => continue, 3 => log(3) } }
+        ");
+        assert_eq!(code, vec![
+            Block(BlockType::Empty, vec![
+                Loop(BlockType::Empty, vec![
+                    I32Const(1), I32Eqz, BranchIf(1),
+                    LocalGet(0), LocalSet(1),
+                    Block(BlockType::Empty, vec![
+                        Block(BlockType::Empty, vec![
+                            Block(BlockType::Empty, vec![
+                                Block(BlockType::Empty, vec![
+                                    Block(BlockType::Empty, vec![
+                                        LocalGet(1), I32Const(1), I32Sub, BranchTable(vec![0, 1, 2], 3),
+                                    ]),
+                                    Branch(5), Branch(3),
+                                ]),
+                                Branch(3), Branch(2),
+                            ]),
+                            I32Const(3), Call(0), Branch(1),
+                        ]),
+                    ]),
+                    Branch(0),
+                ]),
+            ]),
+        ]);
+    }
+
+    #[test]
+    fn if_chain_match_in_loop() {
+        use Instruction::*;
+
+        // Each case after the first is nested one if deeper
+        let code = compile_last_function("
+            import fn env log(x: Int);
+            fn f(x: Int) while true { match x { 1 => continue, 20 => break, _ => log(3) } }
+        ");
+        assert_eq!(code, vec![
+            Block(BlockType::Empty, vec![
+                Loop(BlockType::Empty, vec![
+                    I32Const(1), I32Eqz, BranchIf(1),
+                    LocalGet(0), LocalSet(1),
+                    LocalGet(1), I32Const(1), I32Eq,
+                    IfElse(BlockType::Empty, vec![Branch(1)], vec![
+                        LocalGet(1), I32Const(20), I32Eq,
+                        IfElse(BlockType::Empty, vec![Branch(3)], vec![I32Const(3), Call(0)]),
+                    ]),
+                    Branch(0),
+                ]),
+            ]),
+        ]);
+    }
+}
@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::marker::PhantomData;
+
+// A range of bytes in the source code, which a node was parsed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    // The smallest span which covers both this span and another.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+// A compiler pass which reports errors about the source.
+pub trait Stage {
+    // What the pass's errors are called, like "type" for "type error: ...".
+    const NAME: &'static str;
+}
+
+// An error from one of the compiler's passes, pointing at the source which
+// caused it if that's known.
+pub struct SpannedError<S> {
+    reason: String,
+    span: Option<Span>,
+    stage: PhantomData<S>,
+}
+
+impl<S: Stage> SpannedError<S> {
+    pub fn new<R: Into<String>>(reason: R) -> SpannedError<S> {
+        SpannedError { reason: reason.into(), span: None, stage: PhantomData }
+    }
+
+    pub fn at<R: Into<String>>(span: Span, reason: R) -> SpannedError<S> {
+        SpannedError { reason: reason.into(), span: Some(span), stage: PhantomData }
+    }
+
+    // Blames the given span for the error, unless a more precise one is known.
+    pub fn or_at(self, span: Span) -> SpannedError<S> {
+        SpannedError { span: self.span.or(Some(span)), ..self }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    // The error without the name of the pass it came from.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+// These aren't derived, since that would require the stage to implement them too
+impl<S> Debug for SpannedError<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SpannedError").field("reason", &self.reason).field("span", &self.span).finish()
+    }
+}
+
+impl<S> Clone for SpannedError<S> {
+    fn clone(&self) -> SpannedError<S> {
+        SpannedError { reason: self.reason.clone(), span: self.span, stage: PhantomData }
+    }
+}
+
+impl<S: Stage> Display for SpannedError<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} error: {}", S::NAME, self.reason)
+    }
+}
+
+impl<S: Stage> Error for SpannedError<S> {}
+
+// Lets a single error be returned where several can be.
+impl<S> From<SpannedError<S>> for Vec<SpannedError<S>> {
+    fn from(e: SpannedError<S>) -> Vec<SpannedError<S>> {
+        vec![e]
+    }
+}
+
+// Finds the 1-based line and column of a byte offset in the source. Columns
+// count characters, not bytes.
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..floor_char_boundary(source, offset)];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+fn floor_char_boundary(source: &str, offset: usize) -> usize {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+// Renders a message about a span of the source, with the line it starts on
+// and the span underlined:
+//
+//   no function foo
+//    --> main.tarn:3:5
+//     |
+//   3 |     foo(1)
+//     |     ^^^^^^
+//
+// Spans covering more than one line are underlined to the end of the first.
+pub fn render(message: &str, source_name: &str, source: &str, span: Span) -> String {
+    let start = floor_char_boundary(source, span.start);
+    let end = floor_char_boundary(source, span.end.max(span.start));
+    let (line, column) = line_col(source, start);
+
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+    let text = &source[line_start..line_end];
+
+    // Keep tabs in the padding, so the carets line up however they're displayed
+    let padding: String = source[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let carets = "^".repeat(source[start..end.min(line_end)].chars().count().max(1));
+
+    let gutter = " ".repeat(line.to_string().len());
+    format!(
+        "{}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}",
+        message, gutter, source_name, line, column, gutter, line, text, gutter, padding, carets,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{line_col, render, Span};
+
+    #[test]
+    fn line_col_counts_lines_and_characters() {
+        let source = "fn a()\n  b\n";
+        assert_eq!(line_col(source, 0), (1, 1));
+        assert_eq!(line_col(source, 3), (1, 4));
+        assert_eq!(line_col(source, 6), (1, 7));
+        assert_eq!(line_col(source, 7), (2, 1));
+        assert_eq!(line_col(source, 9), (2, 3));
+        assert_eq!(line_col(source, source.len()), (3, 1));
+    }
+
+    #[test]
+    fn line_col_counts_multi_byte_characters_once() {
+        // "é" is two bytes, and "→" is three
+        let source = "é→x";
+        assert_eq!(line_col(source, 2), (1, 2));
+        assert_eq!(line_col(source, 5), (1, 3));
+
+        // Offsets inside a character belong to that character
+        assert_eq!(line_col(source, 1), (1, 1));
+        assert_eq!(line_col(source, 4), (1, 2));
+    }
+
+    #[test]
+    fn line_col_clamps_past_the_end() {
+        assert_eq!(line_col("ab", 10), (1, 3));
+    }
+
+    #[test]
+    fn render_underlines_the_span() {
+        let source = "fn main() {\n    foo(1)\n}\n";
+        let start = source.find("foo").unwrap();
+        assert_eq!(
+            render("no function foo", "main.tarn", source, Span::new(start, start + 6)),
+            "no function foo\n --> main.tarn:2:5\n  |\n2 |     foo(1)\n  |     ^^^^^^",
+        );
+    }
+
+    #[test]
+    fn render_widens_the_gutter_for_long_line_numbers() {
+        let source = format!("{}x", "\n".repeat(11));
+        assert_eq!(
+            render("bad", "a.tarn", &source, Span::new(11, 12)),
+            "bad\n  --> a.tarn:12:1\n   |\n12 | x\n   | ^",
+        );
+    }
+
+    #[test]
+    fn render_keeps_tabs_in_the_padding() {
+        let source = "\t\tx + y";
+        assert_eq!(
+            render("bad", "a.tarn", source, Span::new(2, 7)),
+            "bad\n --> a.tarn:1:3\n  |\n1 | \t\tx + y\n  | \t\t^^^^^",
+        );
+    }
+
+    #[test]
+    fn render_counts_carets_in_characters() {
+        let source = "\"héllo\" + 1";
+        assert_eq!(
+            render("bad", "a.tarn", source, Span::new(0, 8)),
+            "bad\n --> a.tarn:1:1\n  |\n1 | \"héllo\" + 1\n  | ^^^^^^^",
+        );
+    }
+
+    #[test]
+    fn render_underlines_multi_line_spans_to_the_end_of_the_first_line() {
+        let source = "x = {\n  1\n}";
+        assert_eq!(
+            render("bad", "a.tarn", source, Span::new(4, source.len())),
+            "bad\n --> a.tarn:1:5\n  |\n1 | x = {\n  |     ^",
+        );
+    }
+
+    #[test]
+    fn render_marks_empty_spans_with_one_caret() {
+        let source = "fn f(";
+        assert_eq!(
+            render("bad", "a.tarn", source, Span::new(5, 5)),
+            "bad\n --> a.tarn:1:6\n  |\n1 | fn f(\n  |      ^",
+        );
+    }
+}
@@ -0,0 +1,5 @@
+pub mod wasm;
+pub mod semantic_tree;
+pub mod codegen;
+pub mod parser;
+pub mod diagnostic;
@@ -1,254 +1,293 @@
-mod wasm;
-mod semantic_tree;
-mod codegen;
-mod parser;
-
 use std::fs::File;
-use std::io::Write;
-use std::collections::HashMap;
-use std::sync::Arc;
-
-use crate::wasm::{
-    *,
-    core::*,
-    instruction::*,
-    sections::{*, code_section::*, data_section::*, export_section::*, import_section::*, memory_section::*, type_section::*},
-    module::*,
-};
-use crate::semantic_tree::{*, semanticize::*};
-use crate::codegen::*;
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let parsed = parser::tarn_parser::program("
-        import fn wasi_unstable fd_write(fd : Int, ptr : Int, len : Int, out : Int) -> Int;
-
-        fn _start() -> Int {
-            set! 0 8;
-            set! 4 2;
-            set! 8 65;
-            set! 9 10;
-            fd_write(1, 0, 1, 0)
-        }
-    ")?;
-
-    let semantic = parsed.to_semantic_tree()?;
-
-    let mut module = semantic.generate_module()?;
-    module.export_sections[0].exports.push(Export {
-        desc: ExportDesc::Func(1),
-        name: "_start".into(),
-    });
-
-    let wasm = module.generate_wasm();
-
-    let mut f = File::create("full.wasm")?;
-    f.write_all(&wasm[..])?;
-
-    return Ok(());
-
-    let nodes = Node::Root(vec![
-        Node::FunctionDeclaration(
-            FuncId(0),
-            Type::Function(
-                vec![Type::Int, Type::Int, Type::Int, Type::Int],
-                Some(Box::new(Type::Int))
-            ),
-            FunctionDefinition::Import("wasi_unstable".into(), "fd_write".into())
-        ),
-        Node::FunctionDeclaration(
-            FuncId(1),
-            Type::Function(vec![], Some(Box::new(Type::Int))),
-            FunctionDefinition::Implementation(vec![], Box::new(Node::Block(
-                vec![
-                    // Set up array
-                    Node::MemSet(Box::new(Node::IntegerConstant(0)), Box::new(Node::IntegerConstant(8))),
-                    Node::MemSet(Box::new(Node::IntegerConstant(4)), Box::new(Node::IntegerConstant(2))),
-
-                    // Set up string
-                    Node::MemSet(Box::new(Node::IntegerConstant(8)), Box::new(Node::IntegerConstant(65))),
-                    Node::MemSet(Box::new(Node::IntegerConstant(9)), Box::new(Node::IntegerConstant(10))),
-
-                    // Call
-                    Node::Call(FuncId(0), vec![
-                        Node::IntegerConstant(1),
-                        Node::IntegerConstant(0),
-                        Node::IntegerConstant(1),
-                        Node::IntegerConstant(0),
-                    ])
-                ],
-                false
-            )))
-        )
-    ]);
-
-    //     Node(NodeType::Call(
-    //     FuncId(0),
-    //     vec![
-    //         Box::new(Node(NodeType::IntegerConstant(1), ctx.clone())),
-    //         Box::new(Node(NodeType::IntegerConstant(0), ctx.clone())),
-    //         Box::new(Node(NodeType::IntegerConstant(1), ctx.clone())),
-    //         Box::new(Node(NodeType::IntegerConstant(0), ctx.clone())),
-    //     ],
-    // ), ctx.clone());
-
-    // let gen_global_ctx = Arc::new(CodeGenGlobalContext {
-    //     function_table: [
-    //         (FuncId(0), (
-    //             Type::Function(
-    //                 vec![Type::Int, Type::Int, Type::Int, Type::Int], Box::new(Type::Int)
-    //             ),
-    //             vec![],
-    //         )),
-    //     ].iter().cloned().collect(),
-    //     type_table: [
-    //         (
-    //             TypeId(0),
-    //             Type::Function(
-    //                 vec![Type::Int, Type::Int, Type::Int, Type::Int], Box::new(Type::Int)
-    //             ),
-    //         ),
-    //     ].iter().cloned().collect(),
-    // });
-
-    // let genctx = Arc::new(CodeGenContext {
-    //     global: gen_global_ctx.clone(),
-    //     parent: None,
-    //     locals: vec![],
-    // });
-
-    let mut module = nodes.generate_module()?;
-    module.export_sections[0].exports.push(Export {
-        desc: ExportDesc::Func(1),
-        name: "_start".into(),
-    });
-
-    let wasm = module.generate_wasm();
-
-    let mut f = File::create("exp.wasm")?;
-    f.write_all(&wasm[..])?;
-
-    // println!("{:?}", code);
-
-    return Ok(());
-
-    let module = Module {
-        memory_sections: vec![
-            MemorySection {
-                memories: vec![
-                    Memory {
-                        memory_type: Limits {
-                            min: 1,
-                            max: None,
-                        }
-                    }
-                ]
-            }
-        ],
-        import_sections: vec![
-            ImportSection {
-                imports: vec![
-                    Import {
-                        module: "wasi_unstable".into(),
-                        name: "fd_write".into(),
-                        desc: ImportDesc::Func(0),
-                    }
-                ]
-            }
-        ],
-        type_sections: vec![
-            TypeSection {
-                func_types: vec![
-                    FuncType {
-                        parameters: vec![
-                            ValueType::I32,
-                            ValueType::I32,
-                            ValueType::I32,
-                            ValueType::I32,
-                        ],
-                        results: vec![
-                            ValueType::I32,
-                        ]
-                    },
-                    FuncType {
-                        parameters: vec![],
-                        results: vec![],
-                    },
-                ]
-            }
-        ],
-        function_sections: vec![
-            FunctionSection {
-                types: vec![1],
-            }
-        ],
-        export_sections: vec![
-            ExportSection {
-                exports: vec![
-                    Export {
-                        name: "_start".into(),
-                        desc: ExportDesc::Func(1),
-                    },
-                    Export {
-                        name: "memory".into(),
-                        desc: ExportDesc::Mem(0),
-                    }
-                ]
-            }
-        ],
-        data_sections: vec![
-            DataSection {
-                data: vec![
-                    Data {
-                        memory: 0,
-                        expr: Expr {
-                            instructions: vec![
-                                Instruction::I32Const(8),
-                            ]
-                        },
-                        init: "Hello, world!\n".bytes().collect(),
-                    }
-                ]
-            }
-        ],
-        code_sections: vec![
-            CodeSection {
-                codes: vec![
-                    Code {
-                        func: Func {
-                            locals: vec![],
-                            expr: Expr {
-                                instructions: vec![
-                                    // Store a pointer
-                                    Instruction::I32Const(0),
-                                    Instruction::I32Const(8),
-                                    Instruction::I32Store(MemArg { align: 2, offset: 0, }),
-
-                                    // Store the length
-                                    Instruction::I32Const(4),
-                                    Instruction::I32Const("Hello, world!\n".len() as i32),
-                                    Instruction::I32Store(MemArg { align: 2, offset: 0, }),
-
-                                    // Write
-                                    Instruction::I32Const(1), // File descriptor
-                                    Instruction::I32Const(0), // Data pointer
-                                    Instruction::I32Const(1), // Number of strings
-                                    Instruction::I32Const(0), // Where to put the number of bytes written
-                                    Instruction::Call(0),
-
-                                    Instruction::Drop,
-                                ]
-                            },
-                        }
-                    }
-                ]
+use std::io::{self, Read, Write};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::process;
+
+use tarn::wasm::core::WasmCodeGen;
+use tarn::semantic_tree::{Node, Type, semanticize::Semanticize, typecheck::TypeCheck};
+use tarn::codegen::CodeGen;
+use tarn::parser;
+use tarn::diagnostic::{self, Span};
+
+const USAGE: &str = "\
+usage: tarn build [options] [input]
+
+Compiles a tarn source file into a WebAssembly module. If no input is given,
+or the input is `-`, the source is read from stdin.
+
+options:
+    -o, --output <path>     where to write the output (`-` for stdout)
+    -e, --entry <name>      function to export as `_start`, as well as any
+                            names it's exported as in the source. Without
+                            this, a function named `_start` is exported as
+                            `_start`, unless the source exports another
+                            function under that name. The module must end
+                            up exporting `_start` either way, and it must
+                            take no parameters and return nothing.
+        --emit <mode>       what to output: wasm, ast or semantic (default: wasm)
+        --error-limit <n>   how many errors to show at most, or 0 to show
+                            them all (default: 20)
+    -h, --help              print this message";
+
+// What the compiler should output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmitMode {
+    // The raw parse tree, for debugging the parser.
+    Ast,
+
+    // The semantic tree, for debugging semanticize.
+    Semantic,
+
+    // A binary WebAssembly module.
+    Wasm,
+}
+
+// Options for a `tarn build` invocation.
+struct BuildOptions {
+    input: Option<String>,
+    output: Option<String>,
+    entry: Option<String>,
+    emit: EmitMode,
+    error_limit: usize,
+}
+
+#[derive(Debug, Clone)]
+struct UsageError {
+    reason: String,
+}
+
+impl UsageError {
+    fn new<S: Into<String>>(reason: S) -> UsageError {
+        UsageError { reason: reason.into() }
+    }
+}
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl Error for UsageError {}
+
+// An error in the source code, rendered along with the code it's about if we
+// know where that is. It already says which pass it came from, like "type
+// error: ...".
+#[derive(Debug, Clone)]
+struct SourceError {
+    rendered: String,
+}
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.rendered)
+    }
+}
+
+impl Error for SourceError {}
+
+// What the command line asked us to do.
+enum Command {
+    Build(BuildOptions),
+    Help,
+}
+
+fn parse_args(args: &[String]) -> Result<Command, UsageError> {
+    let mut args = args.iter();
+
+    match args.next().map(|x| x.as_str()) {
+        Some("build") => (),
+        Some("-h") | Some("--help") | Some("help") => return Ok(Command::Help),
+        Some(other) => return Err(UsageError::new(format!("unknown command `{}`", other))),
+        None => return Err(UsageError::new("no command given")),
+    }
+
+    let mut options = BuildOptions {
+        input: None,
+        output: None,
+        entry: None,
+        emit: EmitMode::Wasm,
+        error_limit: 20,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args
+            .next()
+            .cloned()
+            .ok_or_else(|| UsageError::new(format!("{} requires a value", name)));
+
+        match arg.as_str() {
+            "-o" | "--output" => options.output = Some(value(arg)?),
+            "-e" | "--entry" => options.entry = Some(value(arg)?),
+            "--emit" => options.emit = match value(arg)?.as_str() {
+                "wasm" => EmitMode::Wasm,
+                "ast" => EmitMode::Ast,
+                "semantic" => EmitMode::Semantic,
+                other => return Err(UsageError::new(format!("unknown emit mode `{}`", other))),
+            },
+            "--error-limit" => options.error_limit = value(arg)?
+                .parse()
+                .map_err(|_| UsageError::new("the error limit must be a whole number"))?,
+            "-h" | "--help" => return Ok(Command::Help),
+            "-" => options.input = None,
+            flag if flag.starts_with('-') => return Err(UsageError::new(format!("unknown option `{}`", flag))),
+            path => {
+                if options.input.is_some() {
+                    return Err(UsageError::new("only one input file may be given"));
+                }
+                options.input = Some(path.into());
             }
-        ]
+        }
+    }
+
+    Ok(Command::Build(options))
+}
+
+fn read_source(input: &Option<String>) -> io::Result<String> {
+    let mut source = String::new();
+    match input {
+        Some(path) => { File::open(path)?.read_to_string(&mut source)?; },
+        None => { io::stdin().read_to_string(&mut source)?; },
+    }
+    Ok(source)
+}
+
+// Wraps a lone error for returning from `build`.
+fn one<E: Into<Box<dyn Error>>>(e: E) -> Vec<Box<dyn Error>> {
+    vec![e.into()]
+}
+
+// Compiles the input, returning every error found if it can't.
+fn build(options: &BuildOptions) -> Result<(), Vec<Box<dyn Error>>> {
+    let source_name = options.input.as_deref().unwrap_or("<stdin>");
+    let source = read_source(&options.input)
+        .map_err(|e| one(format!("can't read `{}`: {}", source_name, e)))?;
+
+    // Points errors at the source they're about, if we know where that is
+    let diagnose = |message: String, span: Option<Span>| -> Box<dyn Error> {
+        let rendered = match span {
+            Some(span) => diagnostic::render(&message, source_name, &source, span),
+            None => message,
+        };
+        Box::new(SourceError { rendered })
     };
 
-    let wasm = module.generate_wasm();
+    let parsed = parser::parse(&source).map_err(|e| one(diagnose(e.to_string(), Some(e.span()))))?;
+    if options.emit == EmitMode::Ast {
+        return write_output(options, format!("{:#?}\n", parsed).as_bytes()).map_err(one);
+    }
+
+    let (mut semantic, semanticize_errors) = parsed.to_partial_semantic_tree();
+    let mut errors = semanticize_errors
+        .iter()
+        .map(|e| diagnose(e.to_string(), e.span()))
+        .collect::<Vec<_>>();
+    if options.emit == EmitMode::Semantic && errors.is_empty() {
+        return write_output(options, format!("{:#?}\n", semantic).as_bytes()).map_err(one);
+    }
+
+    // Whatever converted is type checked even if something else didn't, so
+    // that all the errors are reported together
+    if let Err(type_errors) = semantic.type_check() {
+        errors.extend(type_errors.iter().map(|e| diagnose(e.to_string(), e.span())));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
 
-    let mut f = File::create("out.wasm")?;
-    f.write_all(&wasm[..])?;
+    export_entry(&mut semantic, options.entry.as_deref()).map_err(one)?;
+
+    let module = semantic.generate_module().map_err(|errors| errors
+        .iter()
+        .map(|e| diagnose(e.to_string(), e.span()))
+        .collect::<Vec<_>>())?;
+    write_output(options, &module.generate_wasm()).map_err(one)
+}
+
+// Makes sure the module exports `_start`, which is the entry point of a WASI
+// command. It's the entry function if one was given, or else the function
+// named `_start`, unless the source already exports something as `_start`.
+// Either way, it must take no parameters and return nothing.
+fn export_entry(semantic: &mut Node, entry: Option<&str>) -> Result<(), UsageError> {
+    let entry = match (semantic.exported_function(ENTRY_EXPORT).map(String::from), entry) {
+        (Some(exported), Some(entry)) if entry != exported && entry != ENTRY_EXPORT => return Err(UsageError::new(format!(
+            "can't use `{}` as the entry point, since `{}` is already exported as `{}`", entry, exported, ENTRY_EXPORT,
+        ))),
+        (Some(exported), _) => exported,
+        (None, Some(entry)) if semantic.export_function(entry, ENTRY_EXPORT) => entry.into(),
+        (None, Some(entry)) => return Err(UsageError::new(format!("no entry point function `{}`", entry))),
+        (None, None) if semantic.export_function(ENTRY_EXPORT, ENTRY_EXPORT) => ENTRY_EXPORT.into(),
+        (None, None) => return Err(UsageError::new(format!(
+            "nothing is exported as `{0}`; name a function `{0}`, export one as \"{0}\" or pick one with --entry",
+            ENTRY_EXPORT,
+        ))),
+    };
 
+    match semantic.function_type(&entry) {
+        Some(typ) if *typ != Type::Function(vec![], None) => Err(UsageError::new(format!(
+            "the entry point `{}` has type {}, but it must take no parameters and return nothing", entry, typ,
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// The export a WASI runtime calls to run a command.
+const ENTRY_EXPORT: &str = "_start";
+
+fn write_output(options: &BuildOptions, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    match options.output.as_deref() {
+        Some("-") => io::stdout().write_all(bytes)?,
+        Some(path) => File::create(path)?.write_all(bytes)?,
+
+        // Only binary output gets a default file; debug output goes to the terminal
+        None if options.emit != EmitMode::Wasm => io::stdout().write_all(bytes)?,
+        None => File::create(default_output_path(&options.input))?.write_all(bytes)?,
+    }
     Ok(())
 }
+
+// Picks an output path based on the input path, replacing its extension with `.wasm`.
+fn default_output_path(input: &Option<String>) -> String {
+    match input {
+        Some(path) => std::path::Path::new(path).with_extension("wasm").to_string_lossy().into(),
+        None => "out.wasm".into(),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let options = match parse_args(&args) {
+        Ok(Command::Build(options)) => options,
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(errors) = build(&options) {
+        let shown = match options.error_limit {
+            0 => errors.len(),
+            limit => limit.min(errors.len()),
+        };
+        for (i, e) in errors[..shown].iter().enumerate() {
+            if i > 0 {
+                eprintln!();
+            }
+            if e.is::<SourceError>() {
+                eprintln!("{}", e);
+            } else {
+                eprintln!("error: {}", e);
+            }
+        }
+        if shown < errors.len() {
+            eprintln!("\nerror: too many errors, {} more not shown", errors.len() - shown);
+        }
+        process::exit(1);
+    }
+}
@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use peg::str::LineCol;
+use crate::diagnostic::Span;
+use super::{KEYWORDS, EXPRESSION_KEYWORDS};
+
+// A failure to parse the source, described in terms of what the parser was
+// looking for rather than the raw set of tokens it tried.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    reason: String,
+    span: Span,
+}
+
+impl ParseError {
+    pub fn new(reason: String, span: Span) -> ParseError {
+        ParseError { reason, span }
+    }
+
+    // Describes a failure from the generated parser. Labels which the grammar
+    // gives with `expected!` are preferred over the literal tokens it tried,
+    // and character classes are left out entirely, since they're details of
+    // how tokens are matched.
+    pub fn from_peg(source: &str, e: &peg::error::ParseError<LineCol>) -> ParseError {
+        let offset = e.location.offset;
+        let (found, found_len) = describe_found(source, offset);
+
+        let mut labels = vec![];
+        let mut literals = vec![];
+        for token in e.expected.tokens() {
+            if token.starts_with('"') {
+                literals.push(format!("`{}`", unescape(&token[1..token.len() - 1])));
+            } else if token == "EOF" {
+                literals.push("end of file".into());
+            } else if !token.starts_with('\'') && token != "_" {
+                labels.push(token.to_string());
+            }
+        }
+
+        let mut expected = if labels.is_empty() { literals } else { labels };
+        expected.sort_by_key(|x| (x == "end of file", x.clone()));
+        expected.dedup();
+
+        // An expression which starts with a keyword but doesn't parse can be
+        // blamed on the keyword, which would suggest the keyword was wrong
+        let incomplete = word_at(source, offset)
+            .filter(|w| EXPRESSION_KEYWORDS.contains(w) && expected.iter().any(|x| x == "an expression"));
+
+        let reason = if let Some(keyword) = incomplete {
+            format!("couldn't parse the `{}` expression which starts here", keyword)
+        } else if expected.is_empty() {
+            format!("unexpected {}", found)
+        } else {
+            format!("expected {}, found {}", join_alternatives(&expected), found)
+        };
+        ParseError::new(reason, Span::new(offset, offset + found_len))
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "parse error: {}", self.reason)
+    }
+}
+
+impl Error for ParseError {}
+
+// Describes the source at the point where parsing failed, returning the
+// description and the number of bytes it covers. Words are described whole,
+// and called out if they're keywords, since that's usually why they didn't
+// parse. Anything else is described one character at a time.
+fn describe_found(source: &str, offset: usize) -> (String, usize) {
+    let rest = source.get(offset..).unwrap_or("");
+    match (word_at(source, offset), rest.chars().next()) {
+        (_, None) => ("end of file".into(), 0),
+        (Some(word), _) if KEYWORDS.contains(&word) => (format!("the keyword `{}`", word), word.len()),
+        (Some(word), _) => (format!("`{}`", word), word.len()),
+        (None, Some(c)) if c.is_whitespace() => ("whitespace".into(), c.len_utf8()),
+        (None, Some(c)) => (format!("`{}`", c), c.len_utf8()),
+    }
+}
+
+// Finds the word which starts at an offset in the source, if there is one.
+fn word_at(source: &str, offset: usize) -> Option<&str> {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let rest = source.get(offset..).unwrap_or("");
+    rest.split(|c: char| !is_word(c)).next().filter(|w| !w.is_empty())
+}
+
+// Undoes the escaping peg applies when recording a literal it tried.
+fn unescape(literal: &str) -> String {
+    let mut result = String::new();
+    let mut chars = literal.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+// Joins alternatives into a list like "a, b or c".
+fn join_alternatives(alternatives: &[String]) -> String {
+    match alternatives {
+        [] => String::new(),
+        [only] => only.clone(),
+        [init @ .., last] => format!("{} or {}", init.join(", "), last),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fails part way through an `if` expression without saying why, so the
+    // error ends up at the keyword
+    peg::parser!{
+        grammar quiet_parser() for str {
+            pub rule expr() = quiet!{"if" " x"} / expected!("an expression")
+        }
+    }
+
+    fn describe(source: &str) -> String {
+        let e = quiet_parser::expr(source).expect_err("parse succeeded");
+        ParseError::from_peg(source, &e).to_string()
+    }
+
+    fn parse_error(source: &str) -> ParseError {
+        crate::parser::parse(source).expect_err("parse succeeded")
+    }
+
+    #[test]
+    fn missing_parameters_end() {
+        let e = parse_error("fn f(x: Int {}");
+        assert_eq!(e.to_string(), "parse error: expected `)` after function parameters, found `{`");
+        assert_eq!(e.span(), Span::new(12, 13));
+    }
+
+    #[test]
+    fn missing_semicolon() {
+        let e = parse_error("fn f(x: Int, y: Int) -> Int { x; y z }");
+        assert_eq!(e.to_string(), "parse error: expected `;` between statements, found `z`");
+        assert_eq!(e.span(), Span::new(35, 36));
+    }
+
+    #[test]
+    fn keywords_by_name() {
+        let e = parse_error("let x: Int = 1;");
+        assert_eq!(e.to_string(), "parse error: expected a function, import, global or constant, found the keyword `let`");
+        assert_eq!(e.span(), Span::new(0, 3));
+        assert_eq!(
+            parse_error("fn f() { else }").to_string(),
+            "parse error: expected `}` at the end of the block or an expression, found the keyword `else`",
+        );
+    }
+
+    #[test]
+    fn found_at_the_end() {
+        let e = parse_error("fn f() { \"abc }");
+        assert_eq!(e.to_string(), "parse error: expected `\"` at the end of the string, found end of file");
+        assert_eq!(e.span(), Span::new(15, 15));
+    }
+
+    #[test]
+    fn unescaped_literals() {
+        assert_eq!(unescape("\\\""), "\"");
+        assert_eq!(unescape("\\\\"), "\\");
+        assert_eq!(unescape("->"), "->");
+    }
+
+    #[test]
+    fn alternatives() {
+        let words = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(join_alternatives(&words(&[])), "");
+        assert_eq!(join_alternatives(&words(&["a"])), "a");
+        assert_eq!(join_alternatives(&words(&["a", "b"])), "a or b");
+        assert_eq!(join_alternatives(&words(&["a", "b", "c"])), "a, b or c");
+    }
+
+    #[test]
+    fn incomplete_keyword_expression() {
+        assert_eq!(describe("if y"), "parse error: couldn't parse the `if` expression which starts here");
+        assert_eq!(describe("fn"), "parse error: expected an expression, found the keyword `fn`");
+    }
+}
@@ -1,107 +1,591 @@
 use peg;
+use crate::diagnostic::Span;
 
+pub mod error;
+
+use error::ParseError;
+
+// A node of the parse tree, along with the source it was parsed from.
 #[derive(Debug)]
-pub enum Node {
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
+}
+
+#[derive(Debug)]
+pub enum NodeKind {
     Program(Vec<Node>),
     Identifier(String),
-    IntegerLiteral(i64),
+    IntegerLiteral(String, Option<String>), // digits, type suffix
+    FloatLiteral(String, Option<String>), // digits, type suffix
+    BooleanLiteral(bool),
     Block(Vec<Node>, bool),
     Call(Box<Node>, Vec<Node>),
     FunctionImplementation {
         name: String,
         params: Vec<Node>,
-        return_type: Box<Node>,
-        body: Box<Node>
+        return_type: Option<Box<Node>>,
+        body: Box<Node>,
+        export: Option<String>, // The name the function is exported as, if any
+        doc: Option<String>, // The text of any `///` comments before the function
     },
     FunctionImport {
         name: String,
         module: String,
         params: Vec<Node>,
-        return_type: Box<Node>,
+        return_type: Option<Box<Node>>,
+        doc: Option<String>,
     },
     FunctionParameter(String, Box<Node>),
-    MemSet(Box<Node>, Box<Node>)
+    GlobalDeclaration {
+        name: String,
+        typ: Box<Node>,
+        value: Box<Node>,
+    },
+    ConstantDeclaration {
+        name: String,
+        typ: Box<Node>,
+        value: Box<Node>,
+    },
+    MemSet(MemoryWidth, Box<Node>, Box<Node>),
+    LocalDeclaration {
+        name: String,
+        typ: Box<Node>,
+        value: Box<Node>,
+    },
+    LocalAssignment(String, Box<Node>),
+    BinaryOperation(BinaryOperator, Box<Node>, Box<Node>),
+    UnaryOperation(UnaryOperator, Box<Node>),
+    If(Box<Node>, Box<Node>, Option<Box<Node>>),
+    While(Box<Node>, Box<Node>),
+    Break,
+    Continue,
+    MemGet(MemoryWidth, LoadKind, Box<Node>),
+    StringLiteral(Vec<u8>),
+    StringLength(Box<Node>),
+    Match(Box<Node>, Vec<(Option<i64>, Node)>), // value, arms (None = the default arm)
+    Cast(Box<Node>, Box<Node>), // value, type
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    And,
+    Or,
 }
 
-use Node::*;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Not,
+}
+
+// The number of bits read or written by a memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryWidth {
+    Bits8,
+    Bits16,
+    Bits32,
+    Bits64,
+}
 
+// How a load turns the bits it reads into a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadKind {
+    Unsigned, // An integer, zero-extended to fit
+    Signed, // An integer, sign-extended to fit
+    Float,
+}
+
+use NodeKind::*;
+use BinaryOperator::*;
+use UnaryOperator::*;
+use MemoryWidth::*;
+use LoadKind::*;
+
+// Creates a node parsed from the source between two positions.
+fn node(start: usize, kind: NodeKind, end: usize) -> Node {
+    Node { kind, span: Span::new(start, end) }
+}
+
+// Creates a node which covers the source of its operands.
+fn binary(op: BinaryOperator, left: Node, right: Node) -> Node {
+    let span = left.span.to(right.span);
+    Node { kind: BinaryOperation(op, Box::new(left), Box::new(right)), span }
+}
+
+// Words which can't be used as names. Every keyword the grammar matches with
+// `keyword` must be listed here, so that it isn't parsed as an identifier.
+pub const KEYWORDS: &[&str] = &[
+    "as", "break", "const", "continue", "else", "export", "false", "fn", "global", "if", "import", "let", "match", "true",
+    "while",
+];
+
+// The keywords which start an expression.
+pub const EXPRESSION_KEYWORDS: &[&str] = &["break", "continue", "false", "if", "let", "match", "true", "while"];
+
+// Parses a whole program, describing any failure in terms of the source.
+pub fn parse(source: &str) -> Result<Node, ParseError> {
+    tarn_parser::program(source).map_err(|e| ParseError::from_peg(source, &e))
+}
+
+// Failures are labeled with `expected!` where the tokens the parser tried
+// wouldn't mean much on their own. Labels are preferred over tokens when
+// errors are described, so operators are quiet: an expression can almost
+// always be followed by one, which is rarely what was missing.
 peg::parser!{
     pub grammar tarn_parser() for str {
-        // Whitespace
+        // Whitespace, which includes comments
+
+        rule _() = quiet!{(whitespace_char() / comment())*} comments_closed()
+
+        rule __() = quiet!{(whitespace_char() / comment())+} comments_closed()
+
+        // Whitespace between declarations, which leaves doc comments for the
+        // declaration after it
+        rule declaration_gap() = quiet!{(whitespace_char() / !doc_comment() comment())*} comments_closed()
+
+        rule whitespace_char() = [' ' | '\n' | '\t']
+
+        rule comment() = line_comment() / block_comment()
+
+        rule line_comment() = "//" (!"\n" [_])*
 
-        rule _() = quiet!{[' ' | '\n' | '\t']*}
+        // Block comments nest, so code containing them can be commented out
+        rule block_comment() = "/*" (block_comment() / !"*/" [_])* "*/"
 
-        rule __() = quiet!{[' ' | '\n' | '\t']+}
+        // Whitespace stops at a block comment which is never closed, which is
+        // reported at the end of the source, where the `*/` is missing
+        rule comments_closed() = !"/*" / "/*" [_]* unclosed_comment()
+
+        rule unclosed_comment() = expected!("`*/` at the end of the comment")
+
+        // `////` is an ordinary comment, like in Rust
+        rule doc_comment() -> &'input str
+            = "///" !"/" " "? text:$((!"\n" [_])*)
+            { text }
+
+        rule doc_comments() -> Option<String>
+            = lines:(line:doc_comment() declaration_gap() { line })*
+            { if lines.is_empty() { None } else { Some(lines.join("\n")) } }
 
         // Atoms
 
-        rule reserved_identifier() -> ()
-            = "fn" / "import"
-            { () }
+        // Keywords only match whole words, so `fname` isn't `fn` followed by
+        // `ame`. That means they don't need whitespace after them, as in `if(x)`.
+        rule keyword(k: rule<()>) = k() !identifier_char()
+
+        rule reserved_identifier()
+            = w:$(identifier_start() identifier_char()*)
+            {? if KEYWORDS.contains(&w) { Ok(()) } else { Err("a keyword") } }
+
+        rule identifier_start() = ['a'..='z' | 'A'..='Z' | '_']
+
+        rule identifier_char() = ['a'..='z' | 'A'..='Z' | '_' | '0'..='9']
 
         rule identifier_s() -> String
-            = !reserved_identifier() id:$(['a'..='z' | 'A'..='Z' | '_'] ['a'..='z' | 'A'..='Z' | '_' | '0'..='9']*)
+            = !reserved_identifier() id:$(identifier_start() identifier_char()*)
             { id.into() }
 
+        // An identifier which names something being declared
+        rule name() -> String
+            = quiet!{identifier_s()} / expected!("a name")
+
         rule identifier() -> Node
-            = id:identifier_s()
-            { Identifier(id) }
+            = s:position!() id:identifier_s() e:position!()
+            { node(s, Identifier(id), e) }
+
+        rule string_literal() -> Node
+            = s:position!() "\"" chars:string_char()* string_end() e:position!()
+            { node(s, StringLiteral(chars.concat()), e) }
+
+        rule string_end()
+            = "\"" / expected!("`\"` at the end of the string")
 
+        rule string_char() -> Vec<u8>
+            = "\\n" { vec![b'\n'] }
+            / "\\t" { vec![b'\t'] }
+            / "\\r" { vec![b'\r'] }
+            / "\\0" { vec![0] }
+            / "\\\\" { vec![b'\\'] }
+            / "\\\"" { vec![b'"'] }
+            / "\\x" h:$(['0'..='9' | 'a'..='f' | 'A'..='F']*<2>) { vec![u8::from_str_radix(h, 16).unwrap()] }
+            / c:$(!['"' | '\\'] [_]) { c.as_bytes().to_vec() }
+
+        // Literals are only checked to fit their type during semanticize
         rule integer_literal() -> Node
-            = n:$("-"? ['0'..='9']+)
-            { IntegerLiteral(n.parse().unwrap()) }
+            = s:position!() n:$("-"? ['0'..='9']+) suffix:integer_suffix()? e:position!() !identifier_char()
+            { node(s, IntegerLiteral(n.into(), suffix), e) }
+
+        rule integer_suffix() -> String
+            = s:$("i32" / "i64") { s.into() }
+
+        rule float_literal() -> Node
+            = s:position!() n:$("-"? ['0'..='9']+ "." ['0'..='9']+ (['e' | 'E'] ['+' | '-']? ['0'..='9']+)?)
+              suffix:float_suffix()? e:position!() !identifier_char()
+            { node(s, FloatLiteral(n.into(), suffix), e) }
+            / s:position!() n:$("-"? ['0'..='9']+) suffix:float_suffix() e:position!() !identifier_char()
+            { node(s, FloatLiteral(n.into(), Some(suffix)), e) }
+
+        rule float_suffix() -> String
+            = s:$("f32" / "f64") { s.into() }
+
+        rule boolean_literal() -> Node
+            = s:position!() keyword(<"true">) e:position!() { node(s, BooleanLiteral(true), e) }
+            / s:position!() keyword(<"false">) e:position!() { node(s, BooleanLiteral(false), e) }
 
         // Types
 
-        pub rule typ() -> Node = identifier()
+        pub rule typ() -> Node
+            = quiet!{identifier()} / expected!("a type")
 
         // Expressions - these cascade!
 
         pub rule expr() -> Node
-            = mem_set()
+            = local_declaration()
+
+        rule local_declaration() -> Node
+            = s:position!() keyword(<"let">) _ name:name() _ ":" _ typ:typ() _ "=" _ value:expr() e:position!()
+            { node(s, LocalDeclaration { name, typ: Box::new(typ), value: Box::new(value) }, e) }
+            / local_assignment()
 
+        rule local_assignment() -> Node
+            = s:position!() name:identifier_s() _ "=" !"=" _ value:expr() e:position!()
+            { node(s, LocalAssignment(name, Box::new(value)), e) }
+            / mem_set()
+
+        // The operands are separated by a comma, since with only whitespace
+        // between them, `set! p -1` would subtract 1 from `p`
         pub rule mem_set() -> Node
-            = "set!" __ target:expr() __ value:expr()
-            { MemSet(Box::new(target), Box::new(value)) }
+            = s:position!() width:mem_set_width() _ target:expr() _ mem_set_comma() _ value:expr() e:position!()
+            { node(s, MemSet(width, Box::new(target), Box::new(value)), e) }
+            / binary_operation()
+
+        rule mem_set_comma()
+            = "," / expected!("`,` between the address and the value")
+
+        rule mem_set_width() -> MemoryWidth
+            = "set8!" { Bits8 }
+            / "set16!" { Bits16 }
+            / "set32!" { Bits32 }
+            / "set64!" { Bits64 }
+            / "set!" { Bits32 }
+
+        // Loads are unsigned unless suffixed with `s`, or read a float if
+        // prefixed with `f`
+        rule mem_get() -> (MemoryWidth, LoadKind)
+            = "get8!" { (Bits8, Unsigned) }
+            / "get8s!" { (Bits8, Signed) }
+            / "get16!" { (Bits16, Unsigned) }
+            / "get16s!" { (Bits16, Signed) }
+            / "get64!" { (Bits64, Unsigned) }
+            / "get!" { (Bits32, Unsigned) }
+            / "getf64!" { (Bits64, Float) }
+            / "getf!" { (Bits32, Float) }
+
+        // Operators are listed from lowest to highest precedence
+        rule binary_operation() -> Node = precedence!{
+            l:(@) _ quiet!{"||"} _ r:@ { binary(Or, l, r) }
+            --
+            l:(@) _ quiet!{"&&"} _ r:@ { binary(And, l, r) }
+            --
+            l:(@) _ quiet!{"=="} _ r:@ { binary(Equal, l, r) }
+            l:(@) _ quiet!{"!="} _ r:@ { binary(NotEqual, l, r) }
+            l:(@) _ quiet!{"<="} _ r:@ { binary(LessThanOrEqual, l, r) }
+            l:(@) _ quiet!{">="} _ r:@ { binary(GreaterThanOrEqual, l, r) }
+            l:(@) _ quiet!{"<" !"<"} _ r:@ { binary(LessThan, l, r) }
+            l:(@) _ quiet!{">" !">"} _ r:@ { binary(GreaterThan, l, r) }
+            --
+            l:(@) _ quiet!{"|" !"|"} _ r:@ { binary(BitwiseOr, l, r) }
+            --
+            l:(@) _ quiet!{"^"} _ r:@ { binary(BitwiseXor, l, r) }
+            --
+            l:(@) _ quiet!{"&" !"&"} _ r:@ { binary(BitwiseAnd, l, r) }
+            --
+            l:(@) _ quiet!{"<<"} _ r:@ { binary(ShiftLeft, l, r) }
+            l:(@) _ quiet!{">>"} _ r:@ { binary(ShiftRight, l, r) }
+            --
+            l:(@) _ quiet!{"+"} _ r:@ { binary(Add, l, r) }
+            l:(@) _ quiet!{"-"} _ r:@ { binary(Subtract, l, r) }
+            --
+            l:(@) _ quiet!{"*"} _ r:@ { binary(Multiply, l, r) }
+            l:(@) _ quiet!{"/"} _ r:@ { binary(Divide, l, r) }
+            l:(@) _ quiet!{"%"} _ r:@ { binary(Modulo, l, r) }
+            --
+            x:(@) _ quiet!{keyword(<"as">)} _ t:typ() {
+                let span = x.span.to(t.span);
+                Node { kind: Cast(Box::new(x), Box::new(t)), span }
+            }
+            --
+            s:position!() "!" _ x:@ {
+                let e = x.span.end;
+                node(s, UnaryOperation(Not, Box::new(x)), e)
+            }
+            s:position!() load:mem_get() _ x:@ {
+                let e = x.span.end;
+                node(s, MemGet(load.0, load.1, Box::new(x)), e)
+            }
+            --
+            l:while_loop() { l }
+        }
+
+        rule while_loop() -> Node
+            = s:position!() keyword(<"while">) _ condition:expr() _ body:braced_block() e:position!()
+            { node(s, While(Box::new(condition), Box::new(body)), e) }
+            / match_expression()
+
+        rule match_expression() -> Node
+            = s:position!() keyword(<"match">) _ value:expr() _ "{" _ arms:match_arm() ** (_ "," _) _ ","? _ "}" e:position!()
+            { node(s, Match(Box::new(value), arms), e) }
+            / conditional()
+
+        rule match_arm() -> (Option<i64>, Node)
+            = pattern:match_pattern() _ "=>" _ body:expr()
+            { (pattern, body) }
+
+        rule match_pattern() -> Option<i64>
+            = quiet!{"_" !identifier_char()} { None }
+            / n:$(quiet!{"-"? ['0'..='9']+}) {? n.parse().map(Some).or(Err("a match case that fits in 64 bits")) }
+            / expected!("a number or `_`")
+
+        rule conditional() -> Node
+            = s:position!() keyword(<"if">) _ condition:expr() _ then:braced_block()
+              otherwise:(_ keyword(<"else">) _ branch:(conditional() / braced_block()) { branch })? e:position!()
+            { node(s, If(Box::new(condition), Box::new(then), otherwise.map(Box::new)), e) }
             / block()
 
         rule block() -> Node
-            = "{" _ stmts:expr() ** (_ ";" _) _ term:";"? _ "}"
-            { Block(stmts, term.is_some()) }
-            / call()
+            = braced_block() / call()
+
+        rule braced_block() -> Node
+            = s:position!() "{" _ stmts:expr() ** (_ ";" _) _ term:";"? _ block_end() e:position!()
+            { node(s, Block(stmts, term.is_some()), e) }
+
+        // Another expression where the block should end is almost always a
+        // missing `;`, so say so
+        rule block_end()
+            = "}" / &expr() missing_semicolon() / !expr() unclosed_block()
+
+        rule missing_semicolon()
+            = expected!("`;` between statements")
+
+        rule unclosed_block()
+            = expected!("`}` at the end of the block")
     
-        rule call() -> Node 
-            = target:atom() "(" _ args:expr() ** (_ "," _) _ ")"
-            { Call(Box::new(target), args) }
-            / atom()
+        // The atom is only parsed once, since parsing it again when there's no
+        // argument list would take exponential time in nested brackets
+        rule call() -> Node
+            = s:position!() target:atom() args:call_arguments()? e:position!()
+            {
+                match args {
+                    Some(args) => node(s, Call(Box::new(target), args), e),
+                    None => target,
+                }
+            }
+
+        rule call_arguments() -> Vec<Node>
+            = "(" _ args:expr() ** (_ "," _) _ (")" / expected!("`,` or `)` after an argument"))
+            { args }
 
         rule atom() -> Node
-            = identifier() / integer_literal() / bracketed()
+            = loop_control() / boolean_literal() / string_length() / identifier() / float_literal() / integer_literal() / string_literal() / bracketed()
+            / expected!("an expression")
+
+        rule string_length() -> Node
+            = s:position!() "len!" _ string:string_literal() e:position!()
+            { node(s, StringLength(Box::new(string)), e) }
+
+        rule loop_control() -> Node
+            = s:position!() keyword(<"break">) e:position!() { node(s, Break, e) }
+            / s:position!() keyword(<"continue">) e:position!() { node(s, Continue, e) }
         
         rule bracketed() -> Node
-            = "(" _ e:expr() _ ")"
+            = "(" _ e:expr() _ (")" / expected!("`)` after the expression"))
             { e }
 
         // Top-level
 
         rule function_parameter() -> Node
-            = id:identifier_s() _ ":" _ t:typ()
-            { FunctionParameter(id, Box::new(t)) }
+            = s:position!() id:name() _ ":" _ t:typ() e:position!()
+            { node(s, FunctionParameter(id, Box::new(t)), e) }
+
+        rule return_type() -> Node
+            = "->" _ t:typ()
+            { t }
+
+        // Exported functions use their own name, unless they're given one
+        rule function_export() -> Option<String>
+            = keyword(<"export">) _ external_name:(n:export_name() _ { n })?
+            { external_name }
+
+        rule export_name() -> String
+            = "\"" chars:string_char()* string_end()
+            {? String::from_utf8(chars.concat()).or(Err("a UTF-8 export name")) }
 
-        // TODO: make return type optional
         pub rule function_implementation() -> Node
-            = "fn" __ name:identifier_s()
-              "(" _ params:function_parameter() ** ("," _) _ ")" _ "->" _ return_type:typ()
-              __ body:expr()
-            { FunctionImplementation { name, params, return_type: Box::new(return_type), body: Box::new(body) } }
+            = doc:doc_comments() s:position!() export:function_export()? keyword(<"fn">) _ name:name()
+              "(" _ params:function_parameter() ** ("," _) _ parameters_end() _ return_type:return_type()?
+              _ body:expr() e:position!()
+            {
+                let export = export.map(|external_name| external_name.unwrap_or_else(|| name.clone()));
+                node(s, FunctionImplementation { name, params, return_type: return_type.map(Box::new), body: Box::new(body), export, doc }, e)
+            }
+
+        rule parameters_end()
+            = ")" / expected!("`)` after function parameters")
+
+        rule declaration_end()
+            = ";" / expected!("`;` after the declaration")
 
         pub rule function_import() -> Node
-            = "import" __ "fn" __ module:identifier_s() __ name:identifier_s()
-              "(" _ params:function_parameter() ** ("," _) _ ")" _ "->" _ return_type:typ() _ ";"
-            { FunctionImport { module, name, params, return_type: Box::new(return_type) } }
+            = doc:doc_comments() s:position!() keyword(<"import">) _ keyword(<"fn">) _ module:name() _ name:name()
+              "(" _ params:function_parameter() ** ("," _) _ parameters_end() _ return_type:return_type()? _ declaration_end() e:position!()
+            { node(s, FunctionImport { module, name, params, return_type: return_type.map(Box::new), doc }, e) }
+
+        // Doc comments are allowed before globals and constants, but only
+        // kept for functions
+        pub rule global_declaration() -> Node
+            = doc_comments() s:position!() keyword(<"global">) _ name:name() _ ":" _ typ:typ() _ "=" _ value:expr() _ declaration_end() e:position!()
+            { node(s, GlobalDeclaration { name, typ: Box::new(typ), value: Box::new(value) }, e) }
+
+        pub rule constant_declaration() -> Node
+            = doc_comments() s:position!() keyword(<"const">) _ name:name() _ ":" _ typ:typ() _ "=" _ value:expr() _ declaration_end() e:position!()
+            { node(s, ConstantDeclaration { name, typ: Box::new(typ), value: Box::new(value) }, e) }
 
         pub rule program() -> Node
-            = ";"* _ n:(function_import() / function_implementation()) ** (_ ";"* _) ";"* _
-            { Program(n) }
+            = declaration_gap() (";" declaration_gap())* s:position!()
+              n:(function_import() / function_implementation() / global_declaration() / constant_declaration())
+              ** (declaration_gap() (";" declaration_gap())*) e:position!() _ (";" _)* program_end()
+            { node(s, Program(n), e) }
+
+        rule program_end()
+            = ![_] / expected!("a function, import, global or constant")
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expression(source: &str) -> Node {
+        tarn_parser::expr(source).expect("parse failed")
     }
-}
\ No newline at end of file
+
+    fn parse_error(source: &str) -> String {
+        parse(source).expect_err("parse succeeded").to_string()
+    }
+
+    // The doc comments kept for each declaration in a program.
+    fn docs(source: &str) -> Vec<Option<String>> {
+        match parse(source).expect("parse failed").kind {
+            Program(declarations) => declarations
+                .into_iter()
+                .map(|declaration| match declaration.kind {
+                    FunctionImplementation { doc, .. } | FunctionImport { doc, .. } => doc,
+                    _ => None,
+                })
+                .collect(),
+            other => panic!("parsed as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn nested_block_comments() {
+        assert!(parse("fn f() -> Int /* a /* b */ c */ 1 // d").is_ok());
+        assert!(matches!(expression("1 + /* /* */ */ 2").kind, BinaryOperation(Add, ..)));
+    }
+
+    #[test]
+    fn unclosed_block_comments() {
+        assert_eq!(
+            parse_error("fn f() {} /* a /* b */"),
+            "parse error: expected `*/` at the end of the comment, found end of file",
+        );
+        assert_eq!(
+            parse_error("fn f() { /* a }"),
+            "parse error: expected `*/` at the end of the comment, found end of file",
+        );
+    }
+
+    #[test]
+    fn doc_comments() {
+        assert_eq!(
+            docs("/// Adds one\n///to x\nfn f(x: Int) -> Int x + 1\n/// Logs\nimport fn env log(x: Int);"),
+            vec![Some("Adds one\nto x".to_string()), Some("Logs".to_string())],
+        );
+        assert_eq!(docs("/// Exported\nexport fn f() {}"), vec![Some("Exported".to_string())]);
+    }
+
+    #[test]
+    fn comments_which_arent_docs() {
+        assert_eq!(docs("//// Not a doc\nfn f() {}"), vec![None]);
+        assert_eq!(docs("/* Not a doc */ fn f() {}"), vec![None]);
+
+        // Doc comments on globals and constants aren't kept, or passed on
+        assert_eq!(docs("/// A global\nglobal g: Int = 1;\nfn f() {}"), vec![None, None]);
+    }
+
+    #[test]
+    fn identifiers_starting_with_keywords() {
+        for name in &["fname", "imported", "format", "iffy", "lettuce"] {
+            assert!(matches!(&expression(name).kind, Identifier(id) if id == name), "{} isn't an identifier", name);
+        }
+        assert!(parse("fn fname(imported: Int) -> Int { let lettuce: Int = imported; lettuce }").is_ok());
+    }
+
+    #[test]
+    fn keyword_as_name() {
+        assert_eq!(
+            parse_error("fn f() { let fn: Int = 1; }"),
+            "parse error: expected a name, found the keyword `fn`",
+        );
+    }
+
+    #[test]
+    fn keywords_before_brackets() {
+        assert!(matches!(expression("if(x) { 1 } else if(y) { 2 } else { 3 }").kind, If(..)));
+        assert!(matches!(expression("while(x) { x = x - 1 }").kind, While(..)));
+        assert!(matches!(expression("match(x) { _ => 1 }").kind, Match(..)));
+    }
+
+    #[test]
+    fn mem_set_operands() {
+        match expression("set64! p, -1i64").kind {
+            MemSet(Bits64, target, value) => {
+                assert!(matches!(&target.kind, Identifier(name) if name == "p"));
+                assert!(matches!(&value.kind, IntegerLiteral(n, Some(suffix)) if n == "-1" && suffix == "i64"));
+            },
+            other => panic!("parsed as {:?}", other),
+        }
+        assert_eq!(
+            parse_error("fn _start() { set64! p -1i64 }"),
+            "parse error: expected `,` between the address and the value, found `}`",
+        );
+    }
+
+    #[test]
+    fn deeply_nested_brackets() {
+        let source = format!("{}a{}", "(a + ".repeat(30), ")".repeat(30));
+        assert!(matches!(expression(&source).kind, BinaryOperation(Add, ..)));
+        assert!(matches!(expression("f(1, (g(2)))").kind, Call(..)));
+    }
+
+    #[test]
+    fn missing_name_after_keyword() {
+        assert_eq!(parse_error("fn(x: Int) {}"), "parse error: expected a name, found `(`");
+        assert_eq!(parse_error("fn"), "parse error: expected a name, found end of file");
+    }
+}
@@ -1,12 +1,18 @@
-use std::collections::HashMap;
-use std::sync::Arc;
-use crate::wasm::{LocalId, TypeId, FuncId, sections::type_section::FuncType, core::ValueType};
+use crate::diagnostic::Span;
+use crate::wasm::{LocalId, FuncId, GlobalId, sections::type_section::FuncType, core::ValueType};
+use std::fmt::{Display, Formatter};
+pub use crate::parser::{BinaryOperator, UnaryOperator, MemoryWidth, LoadKind};
 
 pub mod semanticize;
+pub mod typecheck;
 
-#[derive(Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Type {
     Int,
+    Int64,
+    Float32,
+    Float64,
+    Bool,
     Function(Vec<Type>, Option<Box<Type>>),   
 }
 
@@ -24,23 +30,163 @@ impl Type {
 
     pub fn to_wasm_value_type(&self) -> Option<ValueType> {
         match self {
-            Type::Int => Some(ValueType::I32),
+            Type::Int | Type::Bool => Some(ValueType::I32),
+            Type::Int64 => Some(ValueType::I64),
+            Type::Float32 => Some(ValueType::F32),
+            Type::Float64 => Some(ValueType::F64),
             _ => None,
         }
     }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self, Type::Int | Type::Int64 | Type::Float32 | Type::Float64)
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self, Type::Int | Type::Int64)
+    }
+
+    // Whether `as` can convert a value of this type to another. Numbers convert
+    // freely, and Bool converts to and from Int, as 1 for true and 0 for false.
+    pub fn can_cast_to(&self, target: &Type) -> bool {
+        (self.is_numeric() && target.is_numeric())
+            || matches!((self, target), (Type::Bool, Type::Bool) | (Type::Bool, Type::Int) | (Type::Int, Type::Bool))
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "Int"),
+            Type::Int64 => write!(f, "Int64"),
+            Type::Float32 => write!(f, "Float32"),
+            Type::Float64 => write!(f, "Float64"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Function(params, ret) => {
+                write!(f, "fn(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                write!(f, ")")?;
+                if let Some(ret) = ret {
+                    write!(f, " -> {}", ret)?;
+                }
+                Ok(())
+            },
+        }
+    }
 }
 
+#[derive(Debug, Clone)]
 pub enum FunctionDefinition {
     Import(String, String),
-    Implementation(Vec<Type>, Box<Node>),
+    Implementation(Vec<Type>, Box<Node>, Vec<String>), // non-parameter locals, body, names to export as
+    Invalid, // A function whose body has errors, which is kept so that calls to it can be checked
+}
+
+// A node of the semantic tree, along with the source it came from.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub span: Span,
 }
 
-pub enum Node {
+#[derive(Debug, Clone)]
+pub enum NodeKind {
     Root(Vec<Node>),
-    FunctionDeclaration(FuncId, Type, FunctionDefinition), // ID, type, locals, body
+    FunctionDeclaration(FuncId, String, Type, FunctionDefinition), // ID, name, type, definition
+    GlobalDeclaration(GlobalId, String, Type, Box<Node>), // ID, name, type, initial value
+    ConstantDeclaration(String, Type, Box<Node>), // name, type, value (uses are replaced by the value)
     IntegerConstant(i64),
+    Int64Constant(i64),
+    Float32Constant(f32),
+    Float64Constant(f64),
+    BoolConstant(bool),
     Local(LocalId),
-    MemSet(Box<Node>, Box<Node>),
+    LocalSet(LocalId, Box<Node>),
+    LocalTee(LocalId, Box<Node>), // Like LocalSet, but also evaluates to the value
+    GlobalGet(GlobalId),
+    GlobalTee(GlobalId, Box<Node>), // Sets the global, then evaluates to the value
+    MemSet(MemoryWidth, Box<Node>, Box<Node>), // width, address, value
     Call(FuncId, Vec<Node>),
     Block(Vec<Node>, bool), // bool = is this block terminated?
-}
\ No newline at end of file
+    BinaryOperation(BinaryOperator, Box<Node>, Box<Node>),
+    UnaryOperation(UnaryOperator, Box<Node>),
+    If(Box<Node>, Box<Node>, Option<Box<Node>>), // condition, then, else
+    While(Box<Node>, Box<Node>), // condition, body
+    Break,
+    Continue,
+    MemGet(MemoryWidth, LoadKind, Box<Node>),
+    StringConstant(Vec<u8>), // Evaluates to the address of the string's bytes
+    Match(LocalId, Box<Node>, Vec<(i64, Node)>, Option<Box<Node>>), // scratch local for the value, value, cases, default
+    Cast(Box<Node>, Type), // value, type to convert it to
+}
+
+impl Node {
+    pub fn new(kind: NodeKind, span: Span) -> Node {
+        Node { kind, span }
+    }
+
+    // Exports the function with the given name at the root of a semantic tree
+    // under another name, as well as any it's already exported as. Returns
+    // false if there's no such function.
+    pub fn export_function(&mut self, name: &str, export_name: &str) -> bool {
+        let children = match &mut self.kind {
+            NodeKind::Root(children) => children,
+            _ => return false,
+        };
+
+        for child in children {
+            if let NodeKind::FunctionDeclaration(_, n, _, FunctionDefinition::Implementation(_, _, exports)) = &mut child.kind {
+                if n == name {
+                    if !exports.iter().any(|e| e == export_name) {
+                        exports.push(export_name.into());
+                    }
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Finds the name of the function at the root of a semantic tree which is
+    // exported under the given name, if there is one.
+    pub fn exported_function(&self, export_name: &str) -> Option<&str> {
+        let children = match &self.kind {
+            NodeKind::Root(children) => children,
+            _ => return None,
+        };
+
+        children.iter().find_map(|child| match &child.kind {
+            NodeKind::FunctionDeclaration(_, name, _, FunctionDefinition::Implementation(_, _, exports))
+                if exports.iter().any(|e| e == export_name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    // Finds the type of the function with the given name at the root of a
+    // semantic tree.
+    pub fn function_type(&self, name: &str) -> Option<&Type> {
+        let children = match &self.kind {
+            NodeKind::Root(children) => children,
+            _ => return None,
+        };
+
+        children.iter().find_map(|child| match &child.kind {
+            NodeKind::FunctionDeclaration(_, n, typ, _) if n == name => Some(typ),
+            _ => None,
+        })
+    }
+
+    // Whether this node is a constant which can initialize a global.
+    pub fn is_constant(&self) -> bool {
+        matches!(
+            self.kind,
+            NodeKind::IntegerConstant(_) | NodeKind::Int64Constant(_) | NodeKind::Float32Constant(_)
+                | NodeKind::Float64Constant(_) | NodeKind::BoolConstant(_)
+        )
+    }
+}
@@ -1,135 +1,614 @@
-use super::{Node as SemNode, Type, FunctionDefinition};
-use crate::parser::{Node as ParseNode};
-use crate::wasm::{LocalId, TypeId, FuncId};
-use std::collections::HashMap;
-use std::fmt::{Formatter, Display};
+use super::{Node as SemNode, NodeKind as SemKind, Type, FunctionDefinition};
+use crate::parser::{Node as ParseNode, NodeKind as ParseKind};
+use crate::diagnostic::{SpannedError, Stage};
+use crate::wasm::{FuncId, GlobalId, LocalId};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::sync::Arc;
-use std::error::Error;
 
-#[derive(Debug, Clone)]
-pub struct SemanticizeError {
-    reason: String,
+pub enum SemanticizeStage {}
+
+impl Stage for SemanticizeStage {
+    const NAME: &'static str = "semanticize";
 }
 
-impl SemanticizeError {
-    fn new<S: Into<String>>(reason: S) -> SemanticizeError {
-        SemanticizeError { reason: reason.into() }
-    }
+pub type SemanticizeError = SpannedError<SemanticizeStage>;
+
+// The context used while converting parse nodes into semantic nodes.
+struct SemanticizeContext {
+    // A mapping of function names to their IDs.
+    functions: Arc<HashMap<String, FuncId>>,
+
+    // A mapping of type names to the types they refer to.
+    types: Arc<HashMap<String, Type>>,
+
+    // A mapping of global names to their IDs.
+    globals: Arc<HashMap<String, GlobalId>>,
+
+    // A mapping of constant names to their values, which replace any use of
+    // the constant.
+    constants: Arc<HashMap<String, SemNode>>,
+
+    // The scopes visible from the node being converted, innermost last. Each
+    // maps names to the locals they refer to.
+    scopes: Vec<HashMap<String, LocalId>>,
+
+    // The types of every local in the current function, including parameters.
+    locals: Vec<Type>,
 }
 
-impl Display for SemanticizeError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "code gen error: {}", self.reason)
+impl SemanticizeContext {
+    fn new(
+        functions: Arc<HashMap<String, FuncId>>,
+        types: Arc<HashMap<String, Type>>,
+        globals: Arc<HashMap<String, GlobalId>>,
+        constants: Arc<HashMap<String, SemNode>>,
+    ) -> SemanticizeContext {
+        SemanticizeContext { functions, types, globals, constants, scopes: vec![], locals: vec![] }
+    }
+
+    // Creates a context for converting the body of a function within this one.
+    fn function(&self) -> SemanticizeContext {
+        SemanticizeContext::new(self.functions.clone(), self.types.clone(), self.globals.clone(), self.constants.clone())
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    // Allocates a new local in the innermost scope, shadowing any outer local
+    // with the same name.
+    fn declare_local(&mut self, name: &str, typ: Type) -> Result<LocalId, SemanticizeError> {
+        let id = LocalId(self.locals.len() as u32);
+        let scope = self.scopes
+            .last_mut()
+            .ok_or_else(|| SemanticizeError::new(format!("can't declare {} outside of a function", name)))?;
+        if scope.insert(name.into(), id).is_some() {
+            return Err(SemanticizeError::new(format!("{} is already declared in this scope", name)));
+        }
+        self.locals.push(typ);
+        Ok(id)
+    }
+
+    // Allocates a local which can't be referred to by name, for the compiler's
+    // own use.
+    fn scratch_local(&mut self, typ: Type) -> LocalId {
+        self.locals.push(typ);
+        LocalId(self.locals.len() as u32 - 1)
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<LocalId> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
     }
 }
 
-impl Error for SemanticizeError {}
+// The types which every program can refer to by name.
+fn builtin_types() -> HashMap<String, Type> {
+    [
+        ("Int", Type::Int),
+        ("Int64", Type::Int64),
+        ("Float32", Type::Float32),
+        ("Float64", Type::Float64),
+        ("Bool", Type::Bool),
+    ]
+        .iter()
+        .map(|(name, t)| (name.to_string(), t.clone()))
+        .collect()
+}
 
 pub trait Semanticize {
-    fn to_semantic_tree(&self) -> Result<SemNode, SemanticizeError>;
+    // Converts a program into a semantic tree. Each top-level declaration is
+    // converted even if others have errors, so that all of them are reported.
+    fn to_semantic_tree(&self) -> Result<SemNode, Vec<SemanticizeError>>;
+
+    // Converts as much of a program as possible, returning a tree of the
+    // declarations which converted along with the errors from those which
+    // didn't, so that the rest of the program can still be type checked.
+    // Functions whose bodies have errors are kept as invalid definitions, so
+    // that calls to them can be checked too.
+    fn to_partial_semantic_tree(&self) -> (SemNode, Vec<SemanticizeError>);
+}
+
+// Converts every node in a list, collecting the errors from all of them rather
+// than stopping at the first.
+fn collect_all<T, E: Into<Vec<SemanticizeError>>>(
+    results: impl IntoIterator<Item = Result<T, E>>,
+) -> Result<Vec<T>, Vec<SemanticizeError>> {
+    let mut values = vec![];
+    let mut errors = vec![];
+    for result in results {
+        match result {
+            Ok(value) => values.push(value),
+            Err(e) => errors.extend(e.into()),
+        }
+    }
+    if errors.is_empty() {
+        Ok(values)
+    } else {
+        Err(errors)
+    }
 }
 
 impl ParseNode {
-    // TODO: will need a context when user-defined types exist
-    fn to_semantic_type(&self) -> Result<Type, SemanticizeError> {
-        // TODO
-        Ok(Type::Int)
-    }
-
-    // TODO: will need more fleshed-out context when locals exist
-    fn to_semantic_node(&self, functions: Arc<HashMap<String, FuncId>>) -> Result<SemNode, SemanticizeError> {
-        match self {
-            // TODO: will need to look up locals
-            ParseNode::Identifier(i) => unimplemented!(),
-            ParseNode::Call(target, args) =>
-                match &**target {
-                    ParseNode::Identifier(s) => Ok(SemNode::Call(
-                        functions
-                            .get(s)
-                            .ok_or(SemanticizeError::new(format!("no function {}", s)))?
-                            .clone(),
-                        args
-                            .iter()
-                            .map(|x| x.to_semantic_node(functions.clone()))
-                            .collect::<Result<Vec<_>, _>>()?,
-                    )),
-                    _ => Err(SemanticizeError::new("must call an identifier")),
+    fn to_semantic_type(&self, ctx: &SemanticizeContext) -> Result<Type, SemanticizeError> {
+        match &self.kind {
+            ParseKind::FunctionParameter(_, t) => t.to_semantic_type(ctx),
+            ParseKind::Identifier(i) => ctx.types
+                .get(i)
+                .cloned()
+                .ok_or_else(|| SemanticizeError::at(self.span, format!("no type {}", i))),
+            _ => Err(SemanticizeError::at(self.span, "a type must be a type name")),
+        }
+    }
+
+    // Converts the value of a global or constant, which must be known at
+    // compile time.
+    fn to_constant(&self, ctx: &mut SemanticizeContext, name: &str) -> Result<SemNode, Vec<SemanticizeError>> {
+        let value = self.to_semantic_node(ctx)?;
+        if value.is_constant() {
+            Ok(value)
+        } else {
+            Err(SemanticizeError::at(self.span, format!("the value of {} must be a constant", name)).into())
+        }
+    }
+
+    // Converts the signature of a function declaration.
+    fn to_function_type(&self, ctx: &SemanticizeContext) -> Result<Type, Vec<SemanticizeError>> {
+        let (params, return_type) = match &self.kind {
+            ParseKind::FunctionImplementation { params, return_type, .. } | ParseKind::FunctionImport { params, return_type, .. } =>
+                (params, return_type),
+            _ => return Err(SemanticizeError::at(self.span, "only functions have a signature").into()),
+        };
+
+        let params = collect_all(params.iter().map(|x| x.to_semantic_type(ctx)));
+        let ret = return_type.as_ref().map(|t| t.to_semantic_type(ctx)).transpose();
+        match (params, ret) {
+            (Ok(params), Ok(ret)) => Ok(Type::Function(params, ret.map(Box::new))),
+            (params, ret) => Err(params.err().unwrap_or_default().into_iter().chain(ret.err()).collect()),
+        }
+    }
+
+    fn to_semantic_node(&self, ctx: &mut SemanticizeContext) -> Result<SemNode, Vec<SemanticizeError>> {
+        let kind = self.to_semantic_kind(ctx)
+            .map_err(|errors| errors.into_iter().map(|e| e.or_at(self.span)).collect::<Vec<_>>())?;
+        Ok(SemNode::new(kind, self.span))
+    }
+
+    fn to_semantic_kind(&self, ctx: &mut SemanticizeContext) -> Result<SemKind, Vec<SemanticizeError>> {
+        match &self.kind {
+            // Locals shadow globals, which shadow constants
+            ParseKind::Identifier(i) => {
+                if let Some(id) = ctx.resolve_local(i) {
+                    Ok(SemKind::Local(id))
+                } else if let Some(id) = ctx.globals.get(i) {
+                    Ok(SemKind::GlobalGet(*id))
+                } else if let Some(value) = ctx.constants.get(i) {
+                    Ok(value.kind.clone())
+                } else {
+                    Err(SemanticizeError::new(format!("no local, parameter, global or constant {}", i)).into())
+                }
+            },
+            // The arguments are converted even if the function doesn't exist
+            ParseKind::Call(target, args) =>
+                match &target.kind {
+                    ParseKind::Identifier(s) => {
+                        let id = ctx.functions.get(s).copied();
+                        let args = collect_all(args.iter().map(|x| x.to_semantic_node(ctx)));
+                        match (id, args) {
+                            (Some(id), Ok(args)) => Ok(SemKind::Call(id, args)),
+                            (id, args) => {
+                                let mut errors = args.err().unwrap_or_default();
+                                if id.is_none() {
+                                    errors.insert(0, SemanticizeError::new(format!("no function {}", s)));
+                                }
+                                Err(errors)
+                            },
+                        }
+                    },
+                    _ => Err(SemanticizeError::new("must call an identifier").into()),
                 },
-            ParseNode::IntegerLiteral(i) => Ok(SemNode::IntegerConstant(*i)),
-            ParseNode::Program(s) => Ok(SemNode::Root(s
-                .iter()
-                .map(|x| x.to_semantic_node(functions.clone()))
-                .collect::<Result<Vec<_>, _>>()?)),
-            ParseNode::FunctionImport { module, name, params, return_type } =>
-                Ok(SemNode::FunctionDeclaration(
-                    functions
+            ParseKind::IntegerLiteral(digits, suffix) => {
+                let too_big = |t| SemanticizeError::new(format!("{} doesn't fit in {}", digits, t));
+                match suffix.as_deref() {
+                    None | Some("i32") => Ok(SemKind::IntegerConstant(
+                        digits.parse::<i32>().map_err(|_| too_big(Type::Int))? as i64
+                    )),
+                    Some("i64") => Ok(SemKind::Int64Constant(digits.parse().map_err(|_| too_big(Type::Int64))?)),
+                    Some(other) => Err(SemanticizeError::new(format!("unknown integer suffix {}", other)).into()),
+                }
+            },
+            ParseKind::FloatLiteral(digits, suffix) => {
+                // Parsing saturates to infinity rather than failing
+                let too_big = |t| SemanticizeError::new(format!("{} doesn't fit in {}", digits, t));
+                match suffix.as_deref() {
+                    Some("f32") => match digits.parse::<f32>() {
+                        Ok(f) if f.is_finite() => Ok(SemKind::Float32Constant(f)),
+                        _ => Err(too_big(Type::Float32).into()),
+                    },
+                    None | Some("f64") => match digits.parse::<f64>() {
+                        Ok(f) if f.is_finite() => Ok(SemKind::Float64Constant(f)),
+                        _ => Err(too_big(Type::Float64).into()),
+                    },
+                    Some(other) => Err(SemanticizeError::new(format!("unknown float suffix {}", other)).into()),
+                }
+            },
+            ParseKind::BooleanLiteral(b) => Ok(SemKind::BoolConstant(*b)),
+            ParseKind::Cast(value, typ) =>
+                Ok(SemKind::Cast(Box::new(value.to_semantic_node(ctx)?), typ.to_semantic_type(ctx)?)),
+            ParseKind::Program(s) => Ok(SemKind::Root(collect_all(s.iter().map(|x| x.to_semantic_node(ctx)))?)),
+            ParseKind::FunctionImport { module, name, .. } =>
+                Ok(SemKind::FunctionDeclaration(
+                    *ctx.functions
                         .get(name)
-                        .ok_or(SemanticizeError::new(format!("no internal function mapping for {}", name)))?
-                        .clone(),
-                    Type::Function(
-                        params
-                            .iter()
-                            .map(|x| x.to_semantic_type())
-                            .collect::<Result<Vec<_>, _>>()?,
-                        Some(Box::new(return_type.to_semantic_type()?)),
-                    ),
+                        .ok_or_else(|| SemanticizeError::new(format!("no internal function mapping for {}", name)))?,
+                    name.into(),
+                    self.to_function_type(ctx)?,
                     FunctionDefinition::Import(module.into(), name.into())
                 )),
-            ParseNode::FunctionImplementation { name, params, return_type, body } =>
-                Ok(SemNode::FunctionDeclaration(
-                    functions
+            ParseKind::FunctionImplementation { name, params, body, export, .. } => {
+                let func_type = self.to_function_type(ctx)?;
+                let param_types = match &func_type {
+                    Type::Function(param_types, _) => param_types,
+                    _ => return Err(SemanticizeError::new("a function must have a function type").into()),
+                };
+
+                // Parameters are the first locals of the function, in the outermost scope
+                let mut function_ctx = ctx.function();
+                function_ctx.push_scope();
+                for (param, param_type) in params.iter().zip(param_types) {
+                    if let ParseKind::FunctionParameter(param_name, _) = &param.kind {
+                        function_ctx.declare_local(param_name, param_type.clone()).map_err(|e| e.or_at(param.span))?;
+                    } else {
+                        return Err(SemanticizeError::new("function parameters must be FunctionParameter nodes").into());
+                    }
+                }
+
+                let body = body.to_semantic_node(&mut function_ctx)?;
+
+                Ok(SemKind::FunctionDeclaration(
+                    *ctx.functions
                         .get(name)
-                        .ok_or(SemanticizeError::new(format!("no internal function mapping for {}", name)))?
-                        .clone(),
-                    Type::Function(
-                        params
-                            .iter()
-                            .map(|x| x.to_semantic_type())
-                            .collect::<Result<Vec<_>, _>>()?,
-                        Some(Box::new(return_type.to_semantic_type()?)),
-                    ),
+                        .ok_or_else(|| SemanticizeError::new(format!("no internal function mapping for {}", name)))?,
+                    name.into(),
+                    func_type,
                     FunctionDefinition::Implementation(
-                        vec![], // TODO when locals exist
-                        Box::new(body.to_semantic_node(functions.clone())?),
+                        function_ctx.locals.split_off(params.len()),
+                        Box::new(body),
+                        export.iter().cloned().collect(),
                     )
+                ))
+            },
+            ParseKind::Block(body, term) => {
+                ctx.push_scope();
+                let body = collect_all(body.iter().map(|x| x.to_semantic_node(ctx)));
+                ctx.pop_scope();
+
+                Ok(SemKind::Block(body?, *term))
+            },
+            ParseKind::MemSet(width, target, value) =>
+                Ok(SemKind::MemSet(
+                    *width,
+                    Box::new(target.to_semantic_node(ctx)?),
+                    Box::new(value.to_semantic_node(ctx)?),
                 )),
-            ParseNode::Block(body, term) => 
-                Ok(SemNode::Block(
-                    body
-                        .iter()
-                        .map(|x| x.to_semantic_node(functions.clone()))
-                        .collect::<Result<Vec<_>, _>>()?,
-                    *term,
+            ParseKind::LocalDeclaration { name, typ, value } => {
+                // Convert the value first, so that it can't refer to the local being declared
+                let value = value.to_semantic_node(ctx)?;
+                let typ = typ.to_semantic_type(ctx)?;
+                let id = ctx.declare_local(name, typ)?;
+                Ok(SemKind::LocalSet(id, Box::new(value)))
+            },
+            ParseKind::LocalAssignment(name, value) => {
+                let value = Box::new(value.to_semantic_node(ctx)?);
+                if let Some(id) = ctx.resolve_local(name) {
+                    Ok(SemKind::LocalTee(id, value))
+                } else if let Some(id) = ctx.globals.get(name) {
+                    Ok(SemKind::GlobalTee(*id, value))
+                } else if ctx.constants.contains_key(name) {
+                    Err(SemanticizeError::new(format!("can't assign to constant {}", name)).into())
+                } else {
+                    Err(SemanticizeError::new(format!("no local, parameter or global {}", name)).into())
+                }
+            },
+            ParseKind::GlobalDeclaration { name, typ, value } =>
+                Ok(SemKind::GlobalDeclaration(
+                    *ctx.globals
+                        .get(name)
+                        .ok_or_else(|| SemanticizeError::new(format!("no internal global mapping for {}", name)))?,
+                    name.into(),
+                    typ.to_semantic_type(ctx)?,
+                    Box::new(value.to_constant(ctx, name)?),
+                )),
+            ParseKind::ConstantDeclaration { name, typ, .. } =>
+                Ok(SemKind::ConstantDeclaration(
+                    name.into(),
+                    typ.to_semantic_type(ctx)?,
+                    Box::new(ctx.constants
+                        .get(name)
+                        .cloned()
+                        .ok_or_else(|| SemanticizeError::new(format!("no internal constant mapping for {}", name)))?),
                 )),
-            ParseNode::MemSet(target, value) =>
-                Ok(SemNode::MemSet(
-                    Box::new(target.to_semantic_node(functions.clone())?),
-                    Box::new(value.to_semantic_node(functions.clone())?),
+            ParseKind::BinaryOperation(op, left, right) =>
+                Ok(SemKind::BinaryOperation(
+                    *op,
+                    Box::new(left.to_semantic_node(ctx)?),
+                    Box::new(right.to_semantic_node(ctx)?),
                 )),
-            _ => unimplemented!()
+            ParseKind::UnaryOperation(op, value) =>
+                Ok(SemKind::UnaryOperation(*op, Box::new(value.to_semantic_node(ctx)?))),
+            ParseKind::If(condition, then, otherwise) =>
+                Ok(SemKind::If(
+                    Box::new(condition.to_semantic_node(ctx)?),
+                    Box::new(then.to_semantic_node(ctx)?),
+                    match otherwise {
+                        Some(otherwise) => Some(Box::new(otherwise.to_semantic_node(ctx)?)),
+                        None => None,
+                    },
+                )),
+            ParseKind::While(condition, body) =>
+                Ok(SemKind::While(
+                    Box::new(condition.to_semantic_node(ctx)?),
+                    Box::new(body.to_semantic_node(ctx)?),
+                )),
+            ParseKind::Break => Ok(SemKind::Break),
+            ParseKind::Continue => Ok(SemKind::Continue),
+            ParseKind::MemGet(width, kind, address) =>
+                Ok(SemKind::MemGet(*width, *kind, Box::new(address.to_semantic_node(ctx)?))),
+            ParseKind::StringLiteral(bytes) => Ok(SemKind::StringConstant(bytes.clone())),
+            ParseKind::StringLength(string) => match &string.kind {
+                ParseKind::StringLiteral(bytes) => Ok(SemKind::IntegerConstant(bytes.len() as i64)),
+                _ => Err(SemanticizeError::new("len! must be given a string literal").into()),
+            },
+            ParseKind::Match(value, arms) => {
+                let value = value.to_semantic_node(ctx)?;
+                let mut cases: Vec<(i64, SemNode)> = vec![];
+                let mut default = None;
+                for (pattern, body) in arms {
+                    if default.is_some() {
+                        return Err(SemanticizeError::new("the default arm of a match must come last").into());
+                    }
+                    let body = body.to_semantic_node(ctx)?;
+                    match pattern {
+                        Some(n) if i32::try_from(*n).is_err() =>
+                            return Err(SemanticizeError::new(format!("match case {} doesn't fit in Int", n)).into()),
+                        Some(n) if cases.iter().any(|(case, _)| case == n) =>
+                            return Err(SemanticizeError::new(format!("match has more than one arm for {}", n)).into()),
+                        Some(n) => cases.push((*n, body)),
+                        None => default = Some(Box::new(body)),
+                    }
+                }
+
+                Ok(SemKind::Match(ctx.scratch_local(Type::Int), Box::new(value), cases, default))
+            },
+
+            // Parameters are declared by their function, so they're never converted on their own
+            ParseKind::FunctionParameter(name, _) =>
+                Err(SemanticizeError::new(format!("parameter {} must be part of a function", name)).into()),
         }
     }
 }
 
 impl Semanticize for ParseNode {
-    fn to_semantic_tree(&self) -> Result<SemNode, SemanticizeError> {
-        let program_nodes = if let ParseNode::Program(nodes) = self {
+    fn to_semantic_tree(&self) -> Result<SemNode, Vec<SemanticizeError>> {
+        let (tree, errors) = self.to_partial_semantic_tree();
+        if errors.is_empty() {
+            Ok(tree)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn to_partial_semantic_tree(&self) -> (SemNode, Vec<SemanticizeError>) {
+        let empty = SemNode::new(SemKind::Root(vec![]), self.span);
+        let program_nodes = if let ParseKind::Program(nodes) = &self.kind {
             nodes
         } else {
-            return Err(SemanticizeError::new("must convert a program"));
+            return (empty, vec![SemanticizeError::new("must convert a program")]);
         };
 
-        // Index functions, which can only be declared at the top level
+        // Index functions and globals, which can only be declared at the top level.
+        // Imported functions come first in a wasm module's function index space,
+        // so they're given the first IDs
+        let mut errors = vec![];
         let mut functions: HashMap<String, FuncId> = HashMap::new();
+        let mut globals: HashMap<String, GlobalId> = HashMap::new();
+        for node in program_nodes.iter() {
+            if let ParseKind::FunctionImport { name, .. } = &node.kind {
+                if functions.contains_key(name) {
+                    errors.push(SemanticizeError::at(node.span, format!("function {} is already declared", name)));
+                } else {
+                    functions.insert(name.into(), FuncId(functions.len() as u32));
+                }
+            }
+        }
+
+        // Globals and constants are both used by name in expressions, so they
+        // can't share names either
+        let mut value_names: HashSet<&str> = HashSet::new();
         for node in program_nodes.iter() {
-            match node {
-                ParseNode::FunctionImplementation { name, .. } | ParseNode::FunctionImport { name, .. } =>
-                    functions.insert(name.into(), FuncId(functions.len() as u32)),
+            match &node.kind {
+                ParseKind::FunctionImplementation { name, .. } => {
+                    if functions.contains_key(name) {
+                        errors.push(SemanticizeError::at(node.span, format!("function {} is already declared", name)));
+                    } else {
+                        functions.insert(name.into(), FuncId(functions.len() as u32));
+                    }
+                },
+                ParseKind::FunctionImport { .. } => (),
+                ParseKind::GlobalDeclaration { name, .. } | ParseKind::ConstantDeclaration { name, .. } => {
+                    if !value_names.insert(name) {
+                        errors.push(SemanticizeError::at(node.span, format!("global or constant {} is already declared", name)));
+                    } else if let ParseKind::GlobalDeclaration { .. } = &node.kind {
+                        globals.insert(name.into(), GlobalId(globals.len() as u32));
+                    }
+                },
 
-                _ => return Err(SemanticizeError::new("must only have functions in program")),
+                _ => return (empty, vec![SemanticizeError::at(node.span, "must only have functions, globals and constants in program")]),
             };
         }
+        if !errors.is_empty() {
+            return (empty, errors);
+        }
+        let types = Arc::new(builtin_types());
+
+        // Work out every function's signature before converting any bodies.
+        // Functions whose signatures have errors are left out, like constants
+        // and globals with errors, so that their uses are reported rather than
+        // checked against a declaration which doesn't exist
+        let mut signatures: HashMap<&str, Type> = HashMap::new();
+        let signature_ctx = SemanticizeContext::new(Arc::new(HashMap::new()), types.clone(), Arc::new(HashMap::new()), Arc::new(HashMap::new()));
+        for node in program_nodes.iter() {
+            if let ParseKind::FunctionImplementation { name, .. } | ParseKind::FunctionImport { name, .. } = &node.kind {
+                match node.to_function_type(&signature_ctx) {
+                    Ok(signature) => { signatures.insert(name, signature); },
+                    Err(e) => {
+                        errors.extend(e);
+                        functions.remove(name);
+                    },
+                }
+            }
+        }
+        let functions = Arc::new(functions);
+
+        // Work out the value of each constant, in order so that each can use the ones before it
+        let mut constants: HashMap<String, SemNode> = HashMap::new();
+        for node in program_nodes.iter() {
+            if let ParseKind::ConstantDeclaration { name, value, .. } = &node.kind {
+                let mut ctx = SemanticizeContext::new(functions.clone(), types.clone(), Arc::new(globals.clone()), Arc::new(constants.clone()));
+                match value.to_constant(&mut ctx, name) {
+                    Ok(value) => { constants.insert(name.into(), value); },
+                    Err(e) => errors.extend(e),
+                }
+            }
+        }
+
+        // Globals are converted before functions too, by their position in the program
+        let mut converted_globals: HashMap<usize, SemNode> = HashMap::new();
+        let mut ctx = SemanticizeContext::new(functions.clone(), types.clone(), Arc::new(globals.clone()), Arc::new(constants.clone()));
+        for (i, node) in program_nodes.iter().enumerate() {
+            if let ParseKind::GlobalDeclaration { name, .. } = &node.kind {
+                match node.to_semantic_node(&mut ctx) {
+                    Ok(global) => { converted_globals.insert(i, global); },
+                    Err(e) => {
+                        errors.extend(e);
+                        globals.remove(name);
+                    },
+                }
+            }
+        }
+
+        let mut ctx = SemanticizeContext::new(functions, types, Arc::new(globals), Arc::new(constants));
+        let mut children = vec![];
+        for (i, node) in program_nodes.iter().enumerate() {
+            // Declarations which are left out have already been reported
+            let converted = match &node.kind {
+                ParseKind::GlobalDeclaration { .. } => match converted_globals.remove(&i) {
+                    Some(global) => Ok(global),
+                    None => continue,
+                },
+                ParseKind::ConstantDeclaration { name, .. } if !ctx.constants.contains_key(name) => continue,
+                ParseKind::FunctionImplementation { name, .. } | ParseKind::FunctionImport { name, .. }
+                    if !ctx.functions.contains_key(name) => continue,
+                _ => node.to_semantic_node(&mut ctx),
+            };
+
+            match converted {
+                Ok(child) => children.push(child),
+                Err(e) => {
+                    errors.extend(e);
+                    if let ParseKind::FunctionImplementation { name, .. } = &node.kind {
+                        children.push(SemNode::new(
+                            SemKind::FunctionDeclaration(
+                                ctx.functions[name],
+                                name.clone(),
+                                signatures[name.as_str()].clone(),
+                                FunctionDefinition::Invalid,
+                            ),
+                            node.span,
+                        ));
+                    }
+                },
+            }
+        }
+
+        // Declarations are converted in several passes, so put the errors back in source order
+        errors.sort_by_key(|e| e.span().map(|span| span.start));
+        (SemNode::new(SemKind::Root(children), self.span), errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn errors(source: &str) -> Vec<String> {
+        let parsed = parser::parse(source).expect("parse failed");
+        parsed.to_semantic_tree().expect_err("semanticize succeeded").iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn errors_in_every_statement() {
+        assert_eq!(
+            errors("fn _start() { foo(1); let x: Int = y; bar(2); }"),
+            vec![
+                "semanticize error: no function foo",
+                "semanticize error: no local, parameter, global or constant y",
+                "semanticize error: no function bar",
+            ],
+        );
+    }
+
+    #[test]
+    fn errors_in_calls_and_their_arguments() {
+        assert_eq!(
+            errors("fn _start() { foo(x, 1, y) }"),
+            vec![
+                "semanticize error: no function foo",
+                "semanticize error: no local, parameter, global or constant x",
+                "semanticize error: no local, parameter, global or constant y",
+            ],
+        );
+    }
+
+    #[test]
+    fn errors_in_signatures() {
+        assert_eq!(
+            errors("fn f(x: Foo, y: Int) -> Bar x import fn env g(x: Baz);"),
+            vec!["semanticize error: no type Foo", "semanticize error: no type Bar", "semanticize error: no type Baz"],
+        );
+    }
+
+    #[test]
+    fn partial_tree() {
+        let parsed = parser::parse("fn f() -> Int foo() fn g() -> Bool f() fn h() -> Int { 1 }").expect("parse failed");
+        let (tree, errors) = parsed.to_partial_semantic_tree();
+        assert_eq!(errors.len(), 1);
+
+        // f is kept so that g can be type checked
+        let children = match &tree.kind {
+            SemKind::Root(children) => children,
+            other => panic!("converted to {:?}", other),
+        };
+        let definitions = children.iter().map(|child| match &child.kind {
+            SemKind::FunctionDeclaration(_, name, _, definition) => (name.as_str(), definition),
+            other => panic!("converted to {:?}", other),
+        });
+        let invalid = definitions
+            .map(|(name, definition)| (name, matches!(definition, FunctionDefinition::Invalid)))
+            .collect::<Vec<_>>();
+        assert_eq!(invalid, vec![("f", true), ("g", false), ("h", false)]);
+    }
 
-        self.to_semantic_node(Arc::new(functions))
+    #[test]
+    fn constant_with_a_bad_value() {
+        assert_eq!(
+            errors("const A: Int = B; const B: Int = 1;"),
+            vec!["semanticize error: no local, parameter, global or constant B"],
+        );
     }
 }
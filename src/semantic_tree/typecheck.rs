@@ -0,0 +1,473 @@
+use super::{Node, NodeKind, Type, FunctionDefinition, BinaryOperator, UnaryOperator, MemoryWidth, LoadKind};
+use crate::wasm::{FuncId, GlobalId, LocalId};
+use crate::diagnostic::{SpannedError, Stage};
+use std::collections::HashMap;
+
+pub enum TypeCheckStage {}
+
+impl Stage for TypeCheckStage {
+    const NAME: &'static str = "type";
+}
+
+pub type TypeCheckError = SpannedError<TypeCheckStage>;
+
+// The functions, globals and locals which nodes inside a function can refer to.
+pub trait TypeEnvironment {
+    // The name and type of a function.
+    fn function(&self, id: FuncId) -> Option<(&str, &Type)>;
+
+    // The type of a global.
+    fn global(&self, id: GlobalId) -> Option<&Type>;
+
+    // The type of a local in the current function.
+    fn local(&self, id: LocalId) -> Option<&Type>;
+}
+
+// The environment of a function being type checked.
+struct FunctionEnvironment<'a> {
+    functions: &'a HashMap<FuncId, (String, Type)>,
+    globals: &'a HashMap<GlobalId, Type>,
+    locals: Vec<Type>,
+}
+
+impl<'a> TypeEnvironment for FunctionEnvironment<'a> {
+    fn function(&self, id: FuncId) -> Option<(&str, &Type)> {
+        self.functions.get(&id).map(|(name, t)| (name.as_str(), t))
+    }
+
+    fn global(&self, id: GlobalId) -> Option<&Type> {
+        self.globals.get(&id)
+    }
+
+    fn local(&self, id: LocalId) -> Option<&Type> {
+        self.locals.get(id.0 as usize)
+    }
+}
+
+pub trait TypeCheck {
+    // Checks a whole program. Each declaration is checked even if others have
+    // errors, so that all of them are reported.
+    fn type_check(&self) -> Result<(), Vec<TypeCheckError>>;
+}
+
+impl TypeCheck for Node {
+    fn type_check(&self) -> Result<(), Vec<TypeCheckError>> {
+        let children = if let NodeKind::Root(children) = &self.kind {
+            children
+        } else {
+            return Err(TypeCheckError::new("must type check a root node").into());
+        };
+
+        // Collect the signatures of every function and the type of every global,
+        // so that uses of them can be checked
+        let mut functions = HashMap::new();
+        let mut globals = HashMap::new();
+        for child in children {
+            match &child.kind {
+                NodeKind::FunctionDeclaration(id, name, func_type, _) => {
+                    functions.insert(*id, (name.clone(), func_type.clone()));
+                },
+                NodeKind::GlobalDeclaration(id, _, global_type, _) => {
+                    globals.insert(*id, global_type.clone());
+                },
+                NodeKind::ConstantDeclaration(..) => (),
+                _ => return Err(TypeCheckError::new("root must only contain function, global and constant declarations").into()),
+            }
+        }
+
+        let errors = children
+            .iter()
+            .filter_map(|child| check_declaration(child, &functions, &globals).err())
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Checks a single declaration at the root of a program.
+fn check_declaration(
+    declaration: &Node,
+    functions: &HashMap<FuncId, (String, Type)>,
+    globals: &HashMap<GlobalId, Type>,
+) -> Result<(), TypeCheckError> {
+    match &declaration.kind {
+        NodeKind::FunctionDeclaration(_, name, Type::Function(params, ret), FunctionDefinition::Implementation(locals, body, _)) => {
+            let env = FunctionEnvironment {
+                functions,
+                globals,
+                locals: [params.clone(), locals.clone()].concat(),
+            };
+
+            // The body's final value is the function's return value
+            let body_type = body.result_type(&env)?;
+            if ret.as_deref() != body_type.as_ref() {
+                return Err(TypeCheckError::at(body.span, format!(
+                    "{} should return {}, but its body produces {}",
+                    name, describe_type(ret.as_deref()), describe_type(body_type.as_ref()),
+                )));
+            }
+            Ok(())
+        },
+
+        // Values are constants, so they can't refer to anything else
+        NodeKind::GlobalDeclaration(_, name, t, value) | NodeKind::ConstantDeclaration(name, t, value) => {
+            let env = FunctionEnvironment { functions, globals, locals: vec![] };
+            value.expect_type(t, &env, &format!("the value of {}", name))
+        },
+
+        _ => Ok(()),
+    }
+}
+
+impl Node {
+    // Computes the type of value which this node leaves on the stack, if any,
+    // checking that the types of any operands are valid.
+    pub fn result_type(&self, env: &impl TypeEnvironment) -> Result<Option<Type>, TypeCheckError> {
+        self.kind_result_type(env).map_err(|e| e.or_at(self.span))
+    }
+
+    fn kind_result_type(&self, env: &impl TypeEnvironment) -> Result<Option<Type>, TypeCheckError> {
+        match &self.kind {
+            NodeKind::IntegerConstant(_) | NodeKind::StringConstant(_) => Ok(Some(Type::Int)),
+            NodeKind::Int64Constant(_) => Ok(Some(Type::Int64)),
+            NodeKind::BoolConstant(_) => Ok(Some(Type::Bool)),
+            NodeKind::Float32Constant(_) => Ok(Some(Type::Float32)),
+            NodeKind::Float64Constant(_) => Ok(Some(Type::Float64)),
+
+            NodeKind::Local(id) => Ok(Some(local_type(env, *id)?)),
+
+            NodeKind::GlobalGet(id) => Ok(Some(global_type(env, *id)?)),
+
+            NodeKind::GlobalTee(id, value) => {
+                let t = global_type(env, *id)?;
+                value.expect_type(&t, env, "assigned value")?;
+                Ok(Some(t))
+            },
+
+            NodeKind::LocalSet(id, value) => {
+                value.expect_type(&local_type(env, *id)?, env, "assigned value")?;
+                Ok(None)
+            },
+
+            NodeKind::LocalTee(id, value) => {
+                let t = local_type(env, *id)?;
+                value.expect_type(&t, env, "assigned value")?;
+                Ok(Some(t))
+            },
+
+            NodeKind::MemSet(width, address, value) => {
+                let name = match width {
+                    MemoryWidth::Bits8 => "set8!",
+                    MemoryWidth::Bits16 => "set16!",
+                    MemoryWidth::Bits32 => "set!",
+                    MemoryWidth::Bits64 => "set64!",
+                };
+                address.expect_type(&Type::Int, env, &format!("{} address", name))?;
+
+                // Integers can be truncated to a narrower store, but floats must fill it exactly
+                let value_type = value.result_type(env)?;
+                let fits = match (width, &value_type) {
+                    (MemoryWidth::Bits64, Some(Type::Int64)) | (MemoryWidth::Bits64, Some(Type::Float64)) => true,
+                    (MemoryWidth::Bits64, _) => false,
+                    (MemoryWidth::Bits32, Some(Type::Float32)) => true,
+                    (_, Some(t)) => t.is_integer(),
+                    (_, None) => false,
+                };
+                if !fits {
+                    return Err(TypeCheckError::new(format!(
+                        "{} can't store {}", name, describe_type(value_type.as_ref()),
+                    )));
+                }
+                Ok(None)
+            },
+
+            // Integer loads are sign- or zero-extended to fit an Int, unless they're 64 bits
+            NodeKind::MemGet(width, kind, address) => {
+                address.expect_type(&Type::Int, env, "get! address")?;
+                match (width, kind) {
+                    (MemoryWidth::Bits64, LoadKind::Float) => Ok(Some(Type::Float64)),
+                    (_, LoadKind::Float) => Ok(Some(Type::Float32)),
+                    (MemoryWidth::Bits64, _) => Ok(Some(Type::Int64)),
+                    _ => Ok(Some(Type::Int)),
+                }
+            },
+
+            NodeKind::Call(id, args) => {
+                let (name, func_type) = env.function(*id)
+                    .ok_or_else(|| TypeCheckError::new(format!("no function with index {}", id.0)))?;
+                let (params, ret) = match func_type {
+                    Type::Function(params, ret) => (params, ret),
+                    _ => return Err(TypeCheckError::new(format!("{} is not a function", name))),
+                };
+
+                if args.len() != params.len() {
+                    return Err(TypeCheckError::new(format!(
+                        "{} takes {} argument{}, but {} {} given",
+                        name, params.len(), if params.len() == 1 { "" } else { "s" },
+                        args.len(), if args.len() == 1 { "was" } else { "were" },
+                    )));
+                }
+                for (i, (arg, param)) in args.iter().zip(params).enumerate() {
+                    arg.expect_type(param, env, &format!("argument {} of {}", i + 1, name))?;
+                }
+
+                Ok(ret.as_deref().cloned())
+            },
+
+            NodeKind::Block(ists, term) => {
+                let mut result = None;
+                for ist in ists {
+                    result = ist.result_type(env)?;
+                }
+                Ok(if *term { None } else { result })
+            },
+
+            NodeKind::BinaryOperation(op, left, right) => match op {
+                BinaryOperator::And | BinaryOperator::Or => {
+                    left.expect_type(&Type::Bool, env, "left operand")?;
+                    right.expect_type(&Type::Bool, env, "right operand")?;
+                    Ok(Some(Type::Bool))
+                },
+
+                // Equality works on any value, as long as both sides are the same type
+                BinaryOperator::Equal | BinaryOperator::NotEqual => {
+                    let t = left.result_type(env)?
+                        .ok_or_else(|| TypeCheckError::new("can't compare an expression with no value"))?;
+                    right.expect_type(&t, env, "right operand")?;
+                    Ok(Some(Type::Bool))
+                },
+
+                BinaryOperator::LessThan | BinaryOperator::LessThanOrEqual
+                    | BinaryOperator::GreaterThan | BinaryOperator::GreaterThanOrEqual => {
+                    operand_type(left, right, env, Type::is_numeric, "a number")?;
+                    Ok(Some(Type::Bool))
+                },
+
+                BinaryOperator::Add | BinaryOperator::Subtract | BinaryOperator::Multiply | BinaryOperator::Divide =>
+                    Ok(Some(operand_type(left, right, env, Type::is_numeric, "a number")?)),
+
+                BinaryOperator::Modulo | BinaryOperator::BitwiseAnd | BinaryOperator::BitwiseOr | BinaryOperator::BitwiseXor
+                    | BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight =>
+                    Ok(Some(operand_type(left, right, env, Type::is_integer, "an integer")?)),
+            },
+
+            NodeKind::UnaryOperation(UnaryOperator::Not, value) => {
+                value.expect_type(&Type::Bool, env, "operand of !")?;
+                Ok(Some(Type::Bool))
+            },
+
+            NodeKind::If(condition, then, otherwise) => {
+                condition.expect_type(&Type::Bool, env, "if condition")?;
+                let then_type = then.result_type(env)?;
+                match otherwise {
+                    Some(otherwise) => {
+                        let otherwise_type = otherwise.result_type(env)?;
+                        if then_type != otherwise_type {
+                            return Err(TypeCheckError::new(format!(
+                                "if branches have different types: {} and {}",
+                                describe_type(then_type.as_ref()), describe_type(otherwise_type.as_ref()),
+                            )));
+                        }
+                        Ok(then_type)
+                    },
+
+                    // Without an else branch, the value of the then branch is discarded
+                    None => Ok(None),
+                }
+            },
+
+            NodeKind::While(condition, body) => {
+                condition.expect_type(&Type::Bool, env, "while condition")?;
+                body.result_type(env)?;
+                Ok(None)
+            },
+
+            NodeKind::Match(_, value, cases, default) => {
+                value.expect_type(&Type::Int, env, "match value")?;
+                let mut case_types = vec![];
+                for (_, body) in cases {
+                    case_types.push(body.result_type(env)?);
+                }
+
+                match default {
+                    Some(default) => {
+                        let default_type = default.result_type(env)?;
+                        if let Some(t) = case_types.iter().find(|t| **t != default_type) {
+                            return Err(TypeCheckError::new(format!(
+                                "match arms have different types: {} and {}",
+                                describe_type(t.as_ref()), describe_type(default_type.as_ref()),
+                            )));
+                        }
+                        Ok(default_type)
+                    },
+
+                    // Without a default arm, the value of each arm is discarded
+                    None => Ok(None),
+                }
+            },
+
+            // Any number can be converted to any other, possibly losing precision
+            NodeKind::Cast(value, target) => {
+                let value_type = value.result_type(env)?;
+                match &value_type {
+                    Some(t) if t.can_cast_to(target) => Ok(Some(target.clone())),
+                    _ => Err(TypeCheckError::new(format!(
+                        "can't cast {} to {}", describe_type(value_type.as_ref()), target,
+                    ))),
+                }
+            },
+
+            NodeKind::Break | NodeKind::Continue => Ok(None),
+
+            NodeKind::FunctionDeclaration(..) | NodeKind::GlobalDeclaration(..) | NodeKind::ConstantDeclaration(..) | NodeKind::Root(_) =>
+                Err(TypeCheckError::new("only expressions have a type")),
+        }
+    }
+
+    // Checks that this node produces a value of the given type. The description
+    // says what the node is, for use in the error.
+    fn expect_type(&self, expected: &Type, env: &impl TypeEnvironment, description: &str) -> Result<(), TypeCheckError> {
+        match self.result_type(env)? {
+            Some(ref t) if t == expected => Ok(()),
+            found => Err(TypeCheckError::at(self.span, format!(
+                "{} should be {}, but found {}", description, expected, describe_type(found.as_ref()),
+            ))),
+        }
+    }
+}
+
+// Finds the type of the operands of a binary operator, checking that both are
+// the same type and that the operator accepts it.
+fn operand_type(left: &Node, right: &Node, env: &impl TypeEnvironment, accepts: fn(&Type) -> bool, kind: &str) -> Result<Type, TypeCheckError> {
+    match left.result_type(env)? {
+        Some(t) if accepts(&t) => {
+            right.expect_type(&t, env, "right operand")?;
+            Ok(t)
+        },
+        found => Err(TypeCheckError::new(format!(
+            "left operand should be {}, but found {}", kind, describe_type(found.as_ref()),
+        ))),
+    }
+}
+
+// Describes a possibly-absent type for use in error messages.
+pub fn describe_type(t: Option<&Type>) -> String {
+    match t {
+        Some(t) => t.to_string(),
+        None => "no value".into(),
+    }
+}
+
+fn local_type(env: &impl TypeEnvironment, id: LocalId) -> Result<Type, TypeCheckError> {
+    env.local(id)
+        .cloned()
+        .ok_or_else(|| TypeCheckError::new(format!("no local with index {}", id.0)))
+}
+
+fn global_type(env: &impl TypeEnvironment, id: GlobalId) -> Result<Type, TypeCheckError> {
+    env.global(id)
+        .cloned()
+        .ok_or_else(|| TypeCheckError::new(format!("no global with index {}", id.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use crate::semantic_tree::semanticize::Semanticize;
+
+    fn check(source: &str) -> Result<(), Vec<String>> {
+        let semantic = parser::parse(source)
+            .expect("parse failed")
+            .to_semantic_tree()
+            .expect("semanticize failed");
+        semantic.type_check().map_err(|errors| errors.iter().map(|e| e.to_string()).collect())
+    }
+
+    fn error(source: &str) -> String {
+        let errors = check(source).expect_err("type check succeeded");
+        assert_eq!(errors.len(), 1, "expected one error, found {:?}", errors);
+        errors[0].clone()
+    }
+
+    #[test]
+    fn argument_count() {
+        assert_eq!(
+            error("fn f(x: Int) -> Int x fn g() -> Int f(1, 2)"),
+            "type error: f takes 1 argument, but 2 were given",
+        );
+        assert_eq!(
+            error("fn f(x: Int, y: Int) -> Int x fn g() -> Int f(1)"),
+            "type error: f takes 2 arguments, but 1 was given",
+        );
+    }
+
+    #[test]
+    fn argument_types() {
+        assert_eq!(
+            error("fn f(x: Int, y: Float32) -> Int x fn g() -> Int f(1, 2.0)"),
+            "type error: argument 2 of f should be Float32, but found Float64",
+        );
+    }
+
+    #[test]
+    fn return_type() {
+        assert_eq!(error("fn f() -> Int 1.5"), "type error: f should return Int, but its body produces Float64");
+        assert_eq!(error("fn f() -> Int { 1; }"), "type error: f should return Int, but its body produces no value");
+        assert_eq!(error("fn f() { 1 }"), "type error: f should return no value, but its body produces Int");
+    }
+
+    #[test]
+    fn branch_types() {
+        assert_eq!(
+            error("fn f(x: Bool) -> Int if x { 1 } else { 2i64 }"),
+            "type error: if branches have different types: Int and Int64",
+        );
+        assert_eq!(
+            error("fn f(x: Int) -> Int match x { 1 => 1, 2 => true, _ => 0 }"),
+            "type error: match arms have different types: Bool and Int",
+        );
+    }
+
+    #[test]
+    fn store_width() {
+        assert_eq!(error("fn f() { set64! 0, 1; }"), "type error: set64! can't store Int");
+        assert_eq!(error("fn f() { set! 0, 1.5; }"), "type error: set! can't store Float64");
+        assert_eq!(error("fn f() { set8! 0, 1.5f32; }"), "type error: set8! can't store Float32");
+        assert_eq!(error("fn f() { set! 0, true; }"), "type error: set! can't store Bool");
+        assert_eq!(check("fn f() { set64! 0, 1.5; set! 0, 1.5f32; set8! 0, 1i64; set16! 0, 1; }"), Ok(()));
+    }
+
+    #[test]
+    fn bool_needs_cast_to_int() {
+        assert_eq!(error("fn f() -> Int true + 1"), "type error: left operand should be a number, but found Bool");
+        assert_eq!(error("fn f(x: Int) -> Int x + (x == 1)"), "type error: right operand should be Int, but found Bool");
+        assert_eq!(error("fn f() { let x: Int = 1 < 2; }"), "type error: assigned value should be Int, but found Bool");
+        assert_eq!(check("fn f(x: Int) -> Int x + (x == 1) as Int"), Ok(()));
+    }
+
+    #[test]
+    fn functions_with_semanticize_errors_can_be_called() {
+        let parsed = parser::parse("fn f() -> Int foo() fn g() -> Bool f()").expect("parse failed");
+        let (semantic, _) = parsed.to_partial_semantic_tree();
+        let errors = semantic.type_check().expect_err("type check succeeded");
+        assert_eq!(
+            errors.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+            vec!["type error: g should return Bool, but its body produces Int"],
+        );
+    }
+
+    #[test]
+    fn every_function_is_checked() {
+        assert_eq!(
+            check("fn f() -> Int true fn g() -> Bool 1"),
+            Err(vec![
+                "type error: f should return Int, but its body produces Bool".to_string(),
+                "type error: g should return Bool, but its body produces Int".to_string(),
+            ]),
+        );
+    }
+}
@@ -3,11 +3,11 @@ use leb128;
 pub trait WasmCodeGen {
     fn generate_wasm(&self) -> Vec<u8>;
 
-    fn generate_wasm_seq<T: WasmCodeGen>(&self, items: &Vec<T>) -> Vec<u8> {
-        items.iter().map(|x| x.generate_wasm()).flatten().collect()
+    fn generate_wasm_seq<T: WasmCodeGen>(&self, items: &[T]) -> Vec<u8> {
+        items.iter().flat_map(|x| x.generate_wasm()).collect()
     }
 
-    fn generate_wasm_vec<T: WasmCodeGen>(&self, items: &Vec<T>) -> Vec<u8> {
+    fn generate_wasm_vec<T: WasmCodeGen>(&self, items: &[T]) -> Vec<u8> {
         [
             encode_u32(items.len() as u32),
             self.generate_wasm_seq(items),
@@ -27,6 +27,22 @@ pub fn encode_i32(n: i32) -> Vec<u8> {
     buf
 }
 
+pub fn encode_i64(n: i64) -> Vec<u8> {
+    let mut buf = vec![];
+    leb128::write::signed(&mut buf, n).expect("leb128 conversion failed");
+    buf
+}
+
+// Floats are stored as their raw IEEE-754 bits, so NaN payloads and the sign of
+// zero survive encoding.
+pub fn encode_f32(n: f32) -> Vec<u8> {
+    n.to_bits().to_le_bytes().to_vec()
+}
+
+pub fn encode_f64(n: f64) -> Vec<u8> {
+    n.to_bits().to_le_bytes().to_vec()
+}
+
 impl WasmCodeGen for String {
     fn generate_wasm(&self) -> Vec<u8> {
         [
@@ -42,7 +58,7 @@ impl WasmCodeGen for u32 {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValueType {
     I32,
     I64,
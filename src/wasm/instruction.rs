@@ -1,13 +1,12 @@
-use crate::wasm::core::{WasmCodeGen, ValueType, encode_i32, encode_u32};
+use crate::wasm::core::{WasmCodeGen, ValueType, encode_i32, encode_i64, encode_f32, encode_f64, encode_u32};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Unreachable,
     Nop,
     Block(BlockType, Vec<Instruction>), Loop(BlockType, Vec<Instruction>),
     If(BlockType, Vec<Instruction>), IfElse(BlockType, Vec<Instruction>, Vec<Instruction>),
-    Branch(u32), BranchIf(u32),
-    // TODO: BranchTable,
+    Branch(u32), BranchIf(u32), BranchTable(Vec<u32>, u32), // labels, default label
     Return, Call(u32), CallIndirect(u32),
     Drop, Select,
     LocalGet(u32), LocalSet(u32), LocalTee(u32),
@@ -20,10 +19,24 @@ pub enum Instruction {
     I64Store8(MemArg), I64Store16(MemArg), I64Store32(MemArg),
     MemorySize, MemoryGrow,
     I32Const(i32), I64Const(i64),
-    // TODO: unsure if these float types are correct
     F32Const(f32), F64Const(f64),
-
-    // TODO the remaining instructions
+    I32Eqz, I32Eq, I32Ne, I32LtS, I32LtU, I32GtS, I32GtU, I32LeS, I32LeU, I32GeS, I32GeU,
+    I64Eqz, I64Eq, I64Ne, I64LtS, I64LtU, I64GtS, I64GtU, I64LeS, I64LeU, I64GeS, I64GeU,
+    F32Eq, F32Ne, F32Lt, F32Gt, F32Le, F32Ge,
+    F64Eq, F64Ne, F64Lt, F64Gt, F64Le, F64Ge,
+    I32Clz, I32Ctz, I32Popcnt, I32Add, I32Sub, I32Mul, I32DivS, I32DivU, I32RemS, I32RemU,
+    I32And, I32Or, I32Xor, I32Shl, I32ShrS, I32ShrU, I32Rotl, I32Rotr,
+    I64Clz, I64Ctz, I64Popcnt, I64Add, I64Sub, I64Mul, I64DivS, I64DivU, I64RemS, I64RemU,
+    I64And, I64Or, I64Xor, I64Shl, I64ShrS, I64ShrU, I64Rotl, I64Rotr,
+    F32Abs, F32Neg, F32Ceil, F32Floor, F32Trunc, F32Nearest, F32Sqrt,
+    F32Add, F32Sub, F32Mul, F32Div, F32Min, F32Max, F32Copysign,
+    F64Abs, F64Neg, F64Ceil, F64Floor, F64Trunc, F64Nearest, F64Sqrt,
+    F64Add, F64Sub, F64Mul, F64Div, F64Min, F64Max, F64Copysign,
+    I32WrapI64, I32TruncF32S, I32TruncF32U, I32TruncF64S, I32TruncF64U,
+    I64ExtendI32S, I64ExtendI32U, I64TruncF32S, I64TruncF32U, I64TruncF64S, I64TruncF64U,
+    F32ConvertI32S, F32ConvertI32U, F32ConvertI64S, F32ConvertI64U, F32DemoteF64,
+    F64ConvertI32S, F64ConvertI32U, F64ConvertI64S, F64ConvertI64U, F64PromoteF32,
+    I32ReinterpretF32, I64ReinterpretF64, F32ReinterpretI32, F64ReinterpretI64,
 }
 
 impl Instruction {
@@ -35,7 +48,7 @@ impl Instruction {
             Nop => 0x01,
             Block(_, _) => 0x02, Loop(_, _) => 0x03,
             If(_, _) => 0x04, IfElse(_, _, _) => 0x04,
-            Branch(_) => 0x0C, BranchIf(_) => 0x0D,
+            Branch(_) => 0x0C, BranchIf(_) => 0x0D, BranchTable(_, _) => 0x0E,
             Return => 0x0F, Call(_) => 0x10, CallIndirect(_) => 0x11,
             Drop => 0x1A, Select => 0x1B,
             LocalGet(_) => 0x20, LocalSet(_) => 0x21, LocalTee(_) => 0x22,
@@ -49,6 +62,23 @@ impl Instruction {
             MemorySize => 0x3F, MemoryGrow => 0x40,
             I32Const(_) => 0x41, I64Const(_) => 0x42,
             F32Const(_) => 0x43, F64Const(_) => 0x44,
+            I32Eqz => 0x45, I32Eq => 0x46, I32Ne => 0x47, I32LtS => 0x48, I32LtU => 0x49, I32GtS => 0x4A, I32GtU => 0x4B, I32LeS => 0x4C, I32LeU => 0x4D, I32GeS => 0x4E, I32GeU => 0x4F,
+            I64Eqz => 0x50, I64Eq => 0x51, I64Ne => 0x52, I64LtS => 0x53, I64LtU => 0x54, I64GtS => 0x55, I64GtU => 0x56, I64LeS => 0x57, I64LeU => 0x58, I64GeS => 0x59, I64GeU => 0x5A,
+            F32Eq => 0x5B, F32Ne => 0x5C, F32Lt => 0x5D, F32Gt => 0x5E, F32Le => 0x5F, F32Ge => 0x60,
+            F64Eq => 0x61, F64Ne => 0x62, F64Lt => 0x63, F64Gt => 0x64, F64Le => 0x65, F64Ge => 0x66,
+            I32Clz => 0x67, I32Ctz => 0x68, I32Popcnt => 0x69, I32Add => 0x6A, I32Sub => 0x6B, I32Mul => 0x6C, I32DivS => 0x6D, I32DivU => 0x6E, I32RemS => 0x6F, I32RemU => 0x70,
+            I32And => 0x71, I32Or => 0x72, I32Xor => 0x73, I32Shl => 0x74, I32ShrS => 0x75, I32ShrU => 0x76, I32Rotl => 0x77, I32Rotr => 0x78,
+            I64Clz => 0x79, I64Ctz => 0x7A, I64Popcnt => 0x7B, I64Add => 0x7C, I64Sub => 0x7D, I64Mul => 0x7E, I64DivS => 0x7F, I64DivU => 0x80, I64RemS => 0x81, I64RemU => 0x82,
+            I64And => 0x83, I64Or => 0x84, I64Xor => 0x85, I64Shl => 0x86, I64ShrS => 0x87, I64ShrU => 0x88, I64Rotl => 0x89, I64Rotr => 0x8A,
+            F32Abs => 0x8B, F32Neg => 0x8C, F32Ceil => 0x8D, F32Floor => 0x8E, F32Trunc => 0x8F, F32Nearest => 0x90, F32Sqrt => 0x91,
+            F32Add => 0x92, F32Sub => 0x93, F32Mul => 0x94, F32Div => 0x95, F32Min => 0x96, F32Max => 0x97, F32Copysign => 0x98,
+            F64Abs => 0x99, F64Neg => 0x9A, F64Ceil => 0x9B, F64Floor => 0x9C, F64Trunc => 0x9D, F64Nearest => 0x9E, F64Sqrt => 0x9F,
+            F64Add => 0xA0, F64Sub => 0xA1, F64Mul => 0xA2, F64Div => 0xA3, F64Min => 0xA4, F64Max => 0xA5, F64Copysign => 0xA6,
+            I32WrapI64 => 0xA7, I32TruncF32S => 0xA8, I32TruncF32U => 0xA9, I32TruncF64S => 0xAA, I32TruncF64U => 0xAB,
+            I64ExtendI32S => 0xAC, I64ExtendI32U => 0xAD, I64TruncF32S => 0xAE, I64TruncF32U => 0xAF, I64TruncF64S => 0xB0, I64TruncF64U => 0xB1,
+            F32ConvertI32S => 0xB2, F32ConvertI32U => 0xB3, F32ConvertI64S => 0xB4, F32ConvertI64U => 0xB5, F32DemoteF64 => 0xB6,
+            F64ConvertI32S => 0xB7, F64ConvertI32U => 0xB8, F64ConvertI64S => 0xB9, F64ConvertI64U => 0xBA, F64PromoteF32 => 0xBB,
+            I32ReinterpretF32 => 0xBC, I64ReinterpretF64 => 0xBD, F32ReinterpretI32 => 0xBE, F64ReinterpretI64 => 0xBF,
         }
     }
 
@@ -70,7 +100,14 @@ impl Instruction {
 
             Branch(x) | BranchIf(x) | Call(x) | LocalGet(x) | LocalSet(x) | LocalTee(x) | GlobalGet(x) | GlobalSet(x) => encode_u32(*x),
 
+            BranchTable(labels, default) => [
+                self.generate_wasm_vec(labels),
+                default.generate_wasm()].concat(),
+
             CallIndirect(x) => [encode_u32(*x), vec![0x00]].concat(),
+
+            // The memory index, which must be zero in the MVP
+            MemorySize | MemoryGrow => vec![0x00],
  
             I32Load(m) | I64Load(m) | F32Load(m) | F64Load(m) | I32Load8S(m) | I32Load8U(m) | I32Load16S(m) | I32Load16U(m)
                 | I64Load8S(m) | I64Load8U(m) | I64Load16S(m) | I64Load16U(m) | I64Load32S(m) | I64Load32U(m) 
@@ -78,11 +115,28 @@ impl Instruction {
                 | I64Store16(m) | I64Store32(m) => m.generate_wasm(),
             
             I32Const(x) => encode_i32(*x),
-            
-            // TODO
-            I64Const(_) | F32Const(_) | F64Const(_) => unimplemented!(),
+            I64Const(x) => encode_i64(*x),
+            F32Const(x) => encode_f32(*x),
+            F64Const(x) => encode_f64(*x),
 
-            Unreachable | Nop | MemorySize | MemoryGrow | Drop | Select | Return => vec![],
+            Unreachable | Nop | Drop | Select | Return
+                | I32Eqz | I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU
+                | I64Eqz | I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU
+                | F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge
+                | F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge
+                | I32Clz | I32Ctz | I32Popcnt | I32Add | I32Sub | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU
+                | I32And | I32Or | I32Xor | I32Shl | I32ShrS | I32ShrU | I32Rotl | I32Rotr
+                | I64Clz | I64Ctz | I64Popcnt | I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU
+                | I64And | I64Or | I64Xor | I64Shl | I64ShrS | I64ShrU | I64Rotl | I64Rotr
+                | F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt
+                | F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign
+                | F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt
+                | F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign
+                | I32WrapI64 | I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U
+                | I64ExtendI32S | I64ExtendI32U | I64TruncF32S | I64TruncF32U | I64TruncF64S | I64TruncF64U
+                | F32ConvertI32S | F32ConvertI32U | F32ConvertI64S | F32ConvertI64U | F32DemoteF64
+                | F64ConvertI32S | F64ConvertI32U | F64ConvertI64S | F64ConvertI64U | F64PromoteF32
+                | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => vec![],
         }
     }
 }
@@ -96,7 +150,7 @@ impl WasmCodeGen for Instruction {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum BlockType {
     Empty,
     ValueType(ValueType),
@@ -113,7 +167,7 @@ impl WasmCodeGen for BlockType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MemArg {
     pub align: u32,
     pub offset: u32,
@@ -141,3 +195,94 @@ impl WasmCodeGen for Expr {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::Instruction::{self, *};
+    use crate::wasm::core::WasmCodeGen;
+
+    fn encode(instruction: Instruction) -> Vec<u8> {
+        instruction.generate_wasm()
+    }
+
+    // Decodes the operand of an I64Const back into its value.
+    fn decode_i64(bytes: &[u8]) -> i64 {
+        assert_eq!(bytes[0], 0x42);
+        let mut operand = &bytes[1..];
+        let value = leb128::read::signed(&mut operand).expect("invalid leb128");
+        assert!(operand.is_empty(), "trailing bytes after operand");
+        value
+    }
+
+    #[test]
+    fn i64_const() {
+        assert_eq!(encode(I64Const(0)), vec![0x42, 0x00]);
+        assert_eq!(encode(I64Const(-1)), vec![0x42, 0x7F]);
+        assert_eq!(encode(I64Const(63)), vec![0x42, 0x3F]);
+        assert_eq!(encode(I64Const(64)), vec![0x42, 0xC0, 0x00]);
+        assert_eq!(encode(I64Const(-64)), vec![0x42, 0x40]);
+        assert_eq!(encode(I64Const(-65)), vec![0x42, 0xBF, 0x7F]);
+        assert_eq!(encode(I64Const(624485)), vec![0x42, 0xE5, 0x8E, 0x26]);
+        assert_eq!(
+            encode(I64Const(i64::MIN)),
+            vec![0x42, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7F],
+        );
+        assert_eq!(
+            encode(I64Const(i64::MAX)),
+            vec![0x42, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00],
+        );
+    }
+
+    #[test]
+    fn i64_const_round_trip() {
+        for &n in &[0, 1, -1, 127, 128, -128, -129, i32::MIN as i64, i32::MAX as i64 + 1, i64::MIN, i64::MAX] {
+            assert_eq!(decode_i64(&encode(I64Const(n))), n);
+        }
+    }
+
+    #[test]
+    fn branch_table() {
+        assert_eq!(encode(BranchTable(vec![], 0)), vec![0x0E, 0x00, 0x00]);
+        assert_eq!(encode(BranchTable(vec![0, 1, 2], 3)), vec![0x0E, 0x03, 0x00, 0x01, 0x02, 0x03]);
+        assert_eq!(encode(BranchTable(vec![200, 0], 128)), vec![0x0E, 0x02, 0xC8, 0x01, 0x00, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn f32_const() {
+        assert_eq!(encode(F32Const(0.0)), vec![0x43, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(encode(F32Const(-0.0)), vec![0x43, 0x00, 0x00, 0x00, 0x80]);
+        assert_eq!(encode(F32Const(1.0)), vec![0x43, 0x00, 0x00, 0x80, 0x3F]);
+        assert_eq!(encode(F32Const(-2.5)), vec![0x43, 0x00, 0x00, 0x20, 0xC0]);
+        assert_eq!(encode(F32Const(f32::INFINITY)), vec![0x43, 0x00, 0x00, 0x80, 0x7F]);
+        assert_eq!(encode(F32Const(f32::from_bits(0x7FC0_0000))), vec![0x43, 0x00, 0x00, 0xC0, 0x7F]);
+        assert_eq!(encode(F32Const(f32::from_bits(0xFFC0_1234))), vec![0x43, 0x34, 0x12, 0xC0, 0xFF]);
+    }
+
+    #[test]
+    fn f64_const() {
+        assert_eq!(encode(F64Const(0.0)), vec![0x44, 0, 0, 0, 0, 0, 0, 0, 0x00]);
+        assert_eq!(encode(F64Const(-0.0)), vec![0x44, 0, 0, 0, 0, 0, 0, 0, 0x80]);
+        assert_eq!(encode(F64Const(1.0)), vec![0x44, 0, 0, 0, 0, 0, 0, 0xF0, 0x3F]);
+        assert_eq!(encode(F64Const(f64::NEG_INFINITY)), vec![0x44, 0, 0, 0, 0, 0, 0, 0xF0, 0xFF]);
+        assert_eq!(
+            encode(F64Const(f64::from_bits(0x7FF8_0000_0000_0001))),
+            vec![0x44, 0x01, 0, 0, 0, 0, 0, 0xF8, 0x7F],
+        );
+    }
+
+    #[test]
+    fn float_const_round_trip() {
+        let f32_bits = [0x0000_0000, 0x8000_0000, 0x3F80_0000, 0x7F80_0000, 0x7FC0_0000, 0x7FC0_0001, 0xFFFF_FFFF, 0x0000_0001];
+        for &bits in &f32_bits {
+            let bytes = encode(F32Const(f32::from_bits(bits)));
+            assert_eq!(u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]), bits);
+        }
+
+        let f64_bits = [0x0000_0000_0000_0000, 0x8000_0000_0000_0000, 0x7FF8_0000_0000_0000, 0x7FF8_DEAD_BEEF_0001, 0x0000_0000_0000_0001];
+        for &bits in &f64_bits {
+            let bytes = encode(F64Const(f64::from_bits(bits)));
+            let mut operand = [0; 8];
+            operand.copy_from_slice(&bytes[1..]);
+            assert_eq!(u64::from_le_bytes(operand), bits);
+        }
+    }
+}
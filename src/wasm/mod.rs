@@ -3,9 +3,12 @@ pub mod instruction;
 pub mod module;
 pub mod sections;
 
-#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
 pub struct FuncId(pub u32);
-#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
 pub struct LocalId(pub u32);
-#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
-pub struct TypeId(pub u32);
\ No newline at end of file
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub struct TypeId(pub u32);
+
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub struct GlobalId(pub u32);
@@ -1,11 +1,12 @@
 use super::core::WasmCodeGen;
-use super::sections::{TypeSection, ImportSection, FunctionSection, MemorySection, CodeSection, ExportSection, DataSection};
+use super::sections::{TypeSection, ImportSection, FunctionSection, MemorySection, GlobalSection, CodeSection, ExportSection, DataSection};
 
 pub struct Module {
     pub type_sections: Vec<TypeSection>,
     pub import_sections: Vec<ImportSection>,
     pub function_sections: Vec<FunctionSection>,
     pub memory_sections: Vec<MemorySection>,
+    pub global_sections: Vec<GlobalSection>,
     pub export_sections: Vec<ExportSection>,
     pub code_sections: Vec<CodeSection>,
     pub data_sections: Vec<DataSection>,
@@ -30,6 +31,7 @@ impl WasmCodeGen for Module {
             self.generate_wasm_seq(&self.import_sections),
             self.generate_wasm_seq(&self.function_sections),
             self.generate_wasm_seq(&self.memory_sections),
+            self.generate_wasm_seq(&self.global_sections),
             self.generate_wasm_seq(&self.export_sections),
             self.generate_wasm_seq(&self.code_sections),
             self.generate_wasm_seq(&self.data_sections),
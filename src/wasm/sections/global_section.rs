@@ -0,0 +1,41 @@
+use super::BodySection;
+use crate::wasm::core::{WasmCodeGen, ValueType};
+use crate::wasm::instruction::Expr;
+
+pub struct GlobalSection {
+    pub globals: Vec<Global>,
+}
+
+impl BodySection for GlobalSection {
+    const ID: u8 = 6;
+    type BodyItem = Global;
+    fn body_item(&self) -> &Vec<Self::BodyItem> { &self.globals }
+}
+
+pub struct Global {
+    pub global_type: GlobalType,
+    pub expr: Expr,
+}
+
+impl WasmCodeGen for Global {
+    fn generate_wasm(&self) -> Vec<u8> {
+        [
+            self.global_type.generate_wasm(),
+            self.expr.generate_wasm(),
+        ].concat()
+    }
+}
+
+pub struct GlobalType {
+    pub value_type: ValueType,
+    pub mutable: bool,
+}
+
+impl WasmCodeGen for GlobalType {
+    fn generate_wasm(&self) -> Vec<u8> {
+        [
+            self.value_type.generate_wasm(),
+            vec![if self.mutable { 0x01 } else { 0x00 }],
+        ].concat()
+    }
+}
@@ -12,6 +12,9 @@ pub use function_section::FunctionSection;
 pub mod memory_section;
 pub use memory_section::MemorySection;
 
+pub mod global_section;
+pub use global_section::GlobalSection;
+
 pub mod code_section;
 pub use code_section::CodeSection;
 
@@ -38,7 +41,7 @@ impl<T : BodySection> Section for T {
 
 impl<T : BodySection> WasmCodeGen for T {
     fn generate_wasm(&self) -> Vec<u8> {
-        let body = self.generate_wasm_vec(&self.body_item());
+        let body = self.generate_wasm_vec(self.body_item());
         [
             vec![Self::ID],
             encode_u32(body.len() as u32),